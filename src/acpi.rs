@@ -0,0 +1,432 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! ACPI description of devices.
+//!
+//! Devices described to an x86 guest implement [AcpiDevice](trait.AcpiDevice.html)
+//! and expose it through `Device::acpi()`. The `DeviceManager` then emits
+//! their DSDT device objects, with a `_CRS` built from their allocated
+//! resources and IRQ, along with the MADT entries of the IO APICs and the MCFG
+//! entries of the PCI ECAM windows. The VMM wraps these fragments into the
+//! complete tables.
+
+use crate::device::{IoResource, IoType, IrqResource};
+use std::fmt;
+use std::result;
+
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_WORD_PREFIX: u8 = 0x0b;
+const AML_DWORD_PREFIX: u8 = 0x0c;
+const AML_STRING_PREFIX: u8 = 0x0d;
+const AML_QWORD_PREFIX: u8 = 0x0e;
+const AML_SCOPE_OP: u8 = 0x10;
+const AML_BUFFER_OP: u8 = 0x11;
+const AML_EXT_OP_PREFIX: u8 = 0x5b;
+const AML_DEVICE_OP: u8 = 0x82;
+const AML_ROOT_CHAR: u8 = b'\\';
+
+const MADT_IO_APIC: u8 = 1;
+
+/// Error type for ACPI generation.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The ACPI name is not made of 4 valid characters.
+    InvalidName(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidName(name) => write!(f, "invalid ACPI name {}", name),
+        }
+    }
+}
+
+/// Simplified result type for ACPI generation.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Static table entry of a device.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AcpiTableEntry {
+    /// IO APIC listed in the MADT, at the address of its first MMIO resource.
+    IoApic {
+        /// IO APIC ID.
+        id: u8,
+        /// First GSI handled by the IO APIC.
+        gsi_base: u32,
+    },
+    /// PCI ECAM window listed in the MCFG, at the address of the first MMIO
+    /// resource of the device.
+    PciEcam {
+        /// PCI segment number.
+        segment: u16,
+        /// First bus number decoded by the window.
+        start_bus: u8,
+        /// Last bus number decoded by the window.
+        end_bus: u8,
+    },
+}
+
+/// Trait for devices described in the ACPI tables.
+pub trait AcpiDevice {
+    /// Return the ACPI name of the device object, 4 characters like `COM1`.
+    fn acpi_name(&self) -> String;
+    /// Return the hardware ID, an EISA ID like `PNP0501` or a string ID.
+    fn hid(&self) -> String;
+    /// Return the ID telling apart the devices with the same hardware ID.
+    fn uid(&self) -> u32 {
+        0
+    }
+    /// Return additional AML objects of the device object, e.g. `_CID`.
+    fn aml(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    /// Return the static table entry of the device, if any.
+    fn table_entry(&self) -> Option<AcpiTableEntry> {
+        None
+    }
+}
+
+/// Return the AML device object of `dev`, with the `_CRS` of its allocated
+/// resources and IRQ.
+pub fn device_aml(
+    dev: &dyn AcpiDevice,
+    res: &[IoResource],
+    irq: Option<IrqResource>,
+) -> Result<Vec<u8>> {
+    let mut body = name_string(&dev.acpi_name())?;
+    let hid = dev.hid();
+    body.extend(name_object("_HID", &hid_object(&hid)));
+    body.extend(name_object("_UID", &integer(u64::from(dev.uid()))));
+    body.extend(name_object("_CRS", &resource_template(res, irq)));
+    body.extend(dev.aml());
+
+    let mut aml = vec![AML_EXT_OP_PREFIX, AML_DEVICE_OP];
+    aml.extend(pkg_length(body.len()));
+    aml.extend(body);
+    Ok(aml)
+}
+
+/// Return a `Scope(\_SB_)` AML object holding the `body` objects.
+pub fn system_bus_scope(body: &[u8]) -> Vec<u8> {
+    let mut scope = vec![AML_ROOT_CHAR];
+    scope.extend_from_slice(b"_SB_");
+    scope.extend_from_slice(body);
+    let mut aml = vec![AML_SCOPE_OP];
+    aml.extend(pkg_length(scope.len()));
+    aml.extend(scope);
+    aml
+}
+
+/// Return the MADT or MCFG structure of a table entry located at `addr`.
+pub fn table_entry(entry: AcpiTableEntry, addr: u64) -> Vec<u8> {
+    let mut data = Vec::new();
+    match entry {
+        AcpiTableEntry::IoApic { id, gsi_base } => {
+            data.extend_from_slice(&[MADT_IO_APIC, 12, id, 0]);
+            data.extend_from_slice(&(addr as u32).to_le_bytes());
+            data.extend_from_slice(&gsi_base.to_le_bytes());
+        }
+        AcpiTableEntry::PciEcam {
+            segment,
+            start_bus,
+            end_bus,
+        } => {
+            data.extend_from_slice(&addr.to_le_bytes());
+            data.extend_from_slice(&segment.to_le_bytes());
+            data.extend_from_slice(&[start_bus, end_bus, 0, 0, 0, 0]);
+        }
+    }
+    data
+}
+
+fn name_string(name: &str) -> Result<Vec<u8>> {
+    let valid = name.len() == 4
+        && name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+        && !name.starts_with(|c: char| c.is_ascii_digit());
+    if !valid {
+        return Err(Error::InvalidName(name.to_string()));
+    }
+    Ok(name.as_bytes().to_vec())
+}
+
+fn name_object(name: &str, object: &[u8]) -> Vec<u8> {
+    let mut aml = vec![AML_NAME_OP];
+    aml.extend_from_slice(name.as_bytes());
+    aml.extend_from_slice(object);
+    aml
+}
+
+fn pkg_length(len: usize) -> Vec<u8> {
+    // The encoded length includes its own 1 to 4 bytes.
+    if len + 1 < 1 << 6 {
+        return vec![(len + 1) as u8];
+    }
+    let (bytes, total) = if len + 2 < 1 << 12 {
+        (2, len + 2)
+    } else if len + 3 < 1 << 20 {
+        (3, len + 3)
+    } else {
+        (4, len + 4)
+    };
+    let mut data = vec![((bytes - 1) << 6 | (total & 0xf)) as u8];
+    for i in 1..bytes {
+        data.push((total >> (4 + 8 * (i - 1))) as u8);
+    }
+    data
+}
+
+fn integer(value: u64) -> Vec<u8> {
+    let mut aml = Vec::new();
+    match value {
+        0 => aml.push(AML_ZERO_OP),
+        1 => aml.push(AML_ONE_OP),
+        2..=0xff => aml.extend_from_slice(&[AML_BYTE_PREFIX, value as u8]),
+        0x100..=0xffff => {
+            aml.push(AML_WORD_PREFIX);
+            aml.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            aml.push(AML_DWORD_PREFIX);
+            aml.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        _ => {
+            aml.push(AML_QWORD_PREFIX);
+            aml.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    aml
+}
+
+// EISA IDs are compressed into an integer, other IDs are strings.
+fn hid_object(hid: &str) -> Vec<u8> {
+    let bytes = hid.as_bytes();
+    let is_eisa_id = bytes.len() == 7
+        && bytes[..3].iter().all(|c| c.is_ascii_uppercase())
+        && bytes[3..]
+            .iter()
+            .all(|c| c.is_ascii_digit() || (b'A'..=b'F').contains(c));
+    if !is_eisa_id {
+        let mut aml = vec![AML_STRING_PREFIX];
+        aml.extend_from_slice(bytes);
+        aml.push(0);
+        return aml;
+    }
+    let vendor = bytes[..3]
+        .iter()
+        .fold(0u32, |id, c| id << 5 | u32::from(c - 0x40));
+    let product = u32::from_str_radix(&hid[3..], 16).unwrap_or(0);
+    let mut aml = vec![AML_DWORD_PREFIX];
+    aml.extend_from_slice(&(vendor << 16 | product).to_be_bytes());
+    aml
+}
+
+fn resource_template(res: &[IoResource], irq: Option<IrqResource>) -> Vec<u8> {
+    let mut data = Vec::new();
+    for res in res.iter() {
+        let addr = match res.addr {
+            Some(addr) => addr.0,
+            None => continue,
+        };
+        match res.res_type {
+            IoType::Pio if addr + res.size <= 0x1_0000 && res.size <= 0xff => {
+                // IO port descriptor.
+                data.extend_from_slice(&[0x47, 0x01]);
+                data.extend_from_slice(&(addr as u16).to_le_bytes());
+                data.extend_from_slice(&(addr as u16).to_le_bytes());
+                data.extend_from_slice(&[0x01, res.size as u8]);
+            }
+            IoType::Mmio | IoType::PhysicalMmio if addr + res.size <= 1 << 32 => {
+                // 32-bit fixed memory range descriptor.
+                data.extend_from_slice(&[0x86, 9, 0, 0x01]);
+                data.extend_from_slice(&(addr as u32).to_le_bytes());
+                data.extend_from_slice(&(res.size as u32).to_le_bytes());
+            }
+            IoType::Pio | IoType::Mmio | IoType::PhysicalMmio => {
+                // QWord address space descriptor.
+                let (space, flags) = if res.res_type == IoType::Pio {
+                    (1, 0x03)
+                } else {
+                    (0, 0x01)
+                };
+                data.extend_from_slice(&[0x8a, 43, 0, space, 0x0d, flags]);
+                for value in [0, addr, addr + res.size - 1, 0, res.size].iter() {
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            _ => (),
+        }
+    }
+    // Extended interrupt descriptor, edge triggered for exclusive lines and
+    // level triggered for shared ones.
+    let (line, flags) = match irq {
+        Some(IrqResource::Legacy(Some(line))) => (Some(line), 0x03),
+        Some(IrqResource::SharedLegacy(Some(line))) => (Some(line), 0x09),
+        _ => (None, 0),
+    };
+    if let Some(line) = line {
+        data.extend_from_slice(&[0x89, 6, 0, flags, 1]);
+        data.extend_from_slice(&line.to_le_bytes());
+    }
+    // End tag, without checksum.
+    data.extend_from_slice(&[0x79, 0]);
+
+    let mut body = integer(data.len() as u64);
+    body.extend(data);
+    let mut aml = vec![AML_BUFFER_OP];
+    aml.extend(pkg_length(body.len()));
+    aml.extend(body);
+    aml
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate vm_allocator;
+
+    use self::vm_allocator::SystemAllocator;
+    use super::*;
+    use crate::bus::IoDispatcher;
+    use crate::device;
+    use crate::device::Device;
+    use crate::device_manager::DeviceManager;
+    use crate::pci::PciRoot;
+    use std::sync::{Arc, Mutex};
+    use vm_memory::{GuestAddress, GuestUsize};
+
+    struct Dev {
+        name: &'static str,
+        acpi_name: &'static str,
+        entry: Option<AcpiTableEntry>,
+    }
+
+    impl AcpiDevice for Dev {
+        fn acpi_name(&self) -> String {
+            self.acpi_name.to_string()
+        }
+        fn hid(&self) -> String {
+            "PNP0501".to_string()
+        }
+        fn table_entry(&self) -> Option<AcpiTableEntry> {
+            self.entry
+        }
+    }
+
+    impl Device for Dev {
+        fn name(&self) -> String {
+            self.name.to_string()
+        }
+        fn read(
+            &mut self,
+            _res_index: usize,
+            _offset: GuestUsize,
+            _data: &mut [u8],
+            _io_type: IoType,
+        ) -> device::Result<()> {
+            Ok(())
+        }
+        fn write(
+            &mut self,
+            _res_index: usize,
+            _offset: GuestUsize,
+            _data: &[u8],
+            _io_type: IoType,
+        ) -> device::Result<()> {
+            Ok(())
+        }
+        fn set_resources(&mut self, _res: &[IoResource], _irq: Option<IrqResource>) {}
+        fn acpi(&self) -> Option<&dyn AcpiDevice> {
+            Some(self)
+        }
+    }
+
+    #[test]
+    fn test_aml_encoding() {
+        assert_eq!(pkg_length(0x3e), vec![0x3f]);
+        assert_eq!(pkg_length(0x3f), vec![0x41, 0x04]);
+        assert_eq!(pkg_length(0x1000), vec![0x83, 0x00, 0x01]);
+        assert_eq!(integer(0x1234), vec![AML_WORD_PREFIX, 0x34, 0x12]);
+        assert_eq!(integer(1 << 32)[0], AML_QWORD_PREFIX);
+        // EisaId("PNP0501")
+        assert_eq!(hid_object("PNP0501"), vec![0x0c, 0x41, 0xd0, 0x05, 0x01]);
+        assert_eq!(hid_object("ACPI0007"), b"\x0dACPI0007\0".to_vec());
+        assert!(name_string("com1").is_err());
+        assert!(name_string("1COM").is_err());
+        assert_eq!(
+            system_bus_scope(&[0xaa]),
+            vec![0x10, 0x07, b'\\', b'_', b'S', b'B', b'_', 0xaa]
+        );
+    }
+
+    #[test]
+    fn test_acpi_tables() {
+        let mut sys_res = SystemAllocator::new(
+            Some(GuestAddress(0x100)),
+            Some(0x10000),
+            GuestAddress(0xd000_0000),
+            0x3000_0000,
+            5,
+        )
+        .unwrap();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let uart = Arc::new(Mutex::new(Dev {
+            name: "uart",
+            acpi_name: "COM1",
+            entry: None,
+        }));
+        let mut res_req = vec![IoResource::new(Some(GuestAddress(0x3f8)), 8, IoType::Pio)];
+        dev_mgr
+            .register_device(uart, None, &mut res_req, Some(IrqResource::Legacy(Some(4))))
+            .unwrap();
+        let ioapic = Arc::new(Mutex::new(Dev {
+            name: "ioapic",
+            acpi_name: "APIC",
+            entry: Some(AcpiTableEntry::IoApic { id: 1, gsi_base: 0 }),
+        }));
+        let mut res_req = vec![IoResource::new(
+            Some(GuestAddress(0xfec0_0000)),
+            0x1000,
+            IoType::Mmio,
+        )];
+        dev_mgr
+            .register_device(ioapic, None, &mut res_req, None)
+            .unwrap();
+
+        let dsdt = dev_mgr.dsdt_aml().unwrap();
+        let uart_aml = [
+            0x5b, 0x82, 0x31, b'C', b'O', b'M', b'1', // Device (COM1)
+            0x08, b'_', b'H', b'I', b'D', 0x0c, 0x41, 0xd0, 0x05, 0x01, // EisaId
+            0x08, b'_', b'U', b'I', b'D', 0x00, // Zero
+            0x08, b'_', b'C', b'R', b'S', 0x11, 0x16, 0x0a, 0x13, // Buffer (19)
+            0x47, 0x01, 0xf8, 0x03, 0xf8, 0x03, 0x01, 0x08, // IO (0x3f8, 8)
+            0x89, 0x06, 0x00, 0x03, 0x01, 0x04, 0x00, 0x00, 0x00, // IRQ 4
+            0x79, 0x00,
+        ];
+        assert_eq!(
+            &dsdt[..8],
+            &[0x10, 0x48, 0x06, b'\\', b'_', b'S', b'B', b'_'][..]
+        );
+        // Devices are listed by name.
+        assert_eq!(&dsdt[8..12], &[0x5b, 0x82, 0x2c, b'A'][..]);
+        assert_eq!(&dsdt[dsdt.len() - uart_aml.len()..], &uart_aml[..]);
+
+        assert_eq!(
+            dev_mgr.madt_entries(),
+            vec![1, 12, 1, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0]
+        );
+        assert!(dev_mgr.mcfg_entries().is_empty());
+
+        let root = PciRoot::new("pci0", IoDispatcher::default());
+        let mut res_req = root.resources();
+        dev_mgr
+            .register_device(Arc::new(Mutex::new(root)), None, &mut res_req, None)
+            .unwrap();
+        let mut mcfg = res_req[1].addr.unwrap().0.to_le_bytes().to_vec();
+        mcfg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(dev_mgr.mcfg_entries(), mcfg);
+    }
+}
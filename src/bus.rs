@@ -0,0 +1,868 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! VM exit dispatching.
+//!
+//! [IoDispatcher](struct.IoDispatcher.html) is a cheaply cloneable handle to
+//! the bus maps of a [DeviceManager](../device_manager/struct.DeviceManager.html),
+//! one per address space: PIO, MMIO, PCI configuration space and the custom
+//! ones added by the VMM.
+//! Each vCPU thread owns a clone and dispatches its VM exits through it.
+//!
+//! The bus maps are never modified in place. The device manager builds an
+//! updated copy and publishes it by swapping a pointer, so the exit path only
+//! takes a shared lock long enough to clone an `Arc` and vCPUs never wait on
+//! each other. The only serialization left is the lock of devices implementing
+//! `Device`, which `SharedDevice` implementations do without.
+//!
+//! Accesses no device claims are handled according to the
+//! [Fallback](enum.Fallback.html) of their bus, and counted by address.
+//! Accesses crossing range boundaries are handled according to the
+//! [SpanPolicy](enum.SpanPolicy.html) of their bus.
+//!
+//! After each write to the PCI configuration space of a device, the resources
+//! the guest moved are queued for `DeviceManager::relocate_moved_resources()`.
+
+extern crate vmm_sys_util;
+
+use self::vmm_sys_util::eventfd::EventFd;
+use crate::device::{DeviceHandle, Error as DeviceError, IoType};
+use crate::device_manager::{Error, Range, Result};
+use crate::ioevent::Doorbell;
+use std::collections::btree_map::BTreeMap;
+use std::collections::VecDeque;
+use std::iter;
+use std::ops::{self, Bound};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use vm_memory::{GuestAddress, GuestUsize};
+
+/// Handling of the accesses no device claims on a bus.
+#[derive(Clone, Default)]
+pub enum Fallback {
+    /// Fail the access with `Error::NonExist`, leaving the read data untouched.
+    #[default]
+    Fail,
+    /// Read all ones, as on PC platforms, and ignore writes.
+    AllOnes,
+    /// Read zeros and ignore writes.
+    Zeros,
+    /// Hand the access over to a catch-all device, with resource index 0 and
+    /// the guest address as offset.
+    Forward(DeviceHandle),
+    /// Record the access, see `DeviceManager::take_unclaimed_log()`, and fail
+    /// it with `Error::NonExist`.
+    Record,
+}
+
+/// Handling of the accesses spanning several ranges of a bus, or running
+/// past the end of a range.
+///
+/// When an access gets split or clipped, every part is routed before any is
+/// issued. Parts are then issued in address order and the access stops at
+/// the first failing one, e.g. on a device or `Fallback::Forward` error: the
+/// parts issued before it keep their effects.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SpanPolicy {
+    /// Fail the access with `Error::SpanningAccess`.
+    #[default]
+    Reject,
+    /// Split the access into one access per range, the unclaimed parts going
+    /// to the `Fallback` of the bus. No part is issued if the fallback fails
+    /// an unclaimed one.
+    Split,
+    /// Clip the access to the range it starts in, the bytes past its end
+    /// going to the `Fallback` of the bus. Nothing is issued if the fallback
+    /// fails them.
+    Clip,
+}
+
+/// Maximum number of addresses the unclaimed accesses get counted for,
+/// accesses to further addresses are not counted.
+pub const MAX_UNCLAIMED_ADDRESSES: usize = 1024;
+
+/// Maximum number of unclaimed accesses kept by the `Fallback::Record` buses,
+/// the oldest ones get dropped first.
+pub const MAX_UNCLAIMED_LOG: usize = 1024;
+
+/// An access no device claimed, recorded by a `Fallback::Record` bus.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UnclaimedAccess {
+    /// Guest address of the access.
+    pub addr: GuestAddress,
+    /// Type of the access.
+    pub io_type: IoType,
+    /// Size of the access, in bytes.
+    pub size: usize,
+    /// Written data, `None` for reads.
+    pub data: Option<Vec<u8>>,
+}
+
+/// A device mapped into a bus, with the index of the resource the range belongs to.
+#[derive(Clone)]
+pub(crate) struct BusEntry {
+    /// Index of the resource in the set handed over by `set_resources()`.
+    pub index: usize,
+    /// Offset of the range within the resource, only non zero for aliases.
+    pub offset: GuestUsize,
+    /// The mapped device.
+    pub device: DeviceHandle,
+    /// Doorbells of the device within the range.
+    pub doorbells: Vec<Doorbell>,
+    /// Whether the range is a container holding the ranges of the children
+    /// of the device.
+    pub container: bool,
+}
+
+/// Range mapping for one address space.
+///
+/// Ranges are mapped in priority layers. The ranges of a layer never overlap
+/// each other, and the ranges of the children of a bus device lying within
+/// one of its container ranges sit in the layer right above the container.
+/// Aliases come on top of all the layers.
+#[derive(Clone, Default)]
+pub(crate) struct Bus {
+    layers: BTreeMap<u32, BTreeMap<Range, BusEntry>>,
+    aliases: BTreeMap<Range, BusEntry>,
+    /// Handling of the accesses no range maps.
+    pub fallback: Fallback,
+    /// Handling of the accesses spanning ranges.
+    pub span_policy: SpanPolicy,
+}
+
+impl Bus {
+    /// Insert a range of a child of the bus device `parent`, if any, or report
+    /// `Error::Overlap` if it overlaps any mapped range other than the
+    /// container ranges it lies within. Ranges wrapping past the end of the
+    /// address space are rejected with `Error::Oversize`.
+    pub fn insert(
+        &mut self,
+        range: Range,
+        entry: BusEntry,
+        parent: Option<&DeviceHandle>,
+    ) -> Result<()> {
+        if !range.fits() {
+            return Err(Error::Oversize);
+        }
+        let layer = parent
+            .and_then(|bus| self.container_layer(&range, bus))
+            .map_or(0, |layer| layer + 1);
+        for (mapped_layer, ranges) in self.layers.iter() {
+            for (mapped, mapped_entry) in overlapping(ranges, &range) {
+                if *mapped_layer >= layer || !mapped_entry.container || !mapped.contains(&range) {
+                    return Err(Error::Overlap);
+                }
+            }
+        }
+        self.layers.entry(layer).or_default().insert(range, entry);
+        Ok(())
+    }
+
+    /// Remove a range mapped to `dev`.
+    pub fn remove(&mut self, range: &Range, dev: &DeviceHandle) -> Option<BusEntry> {
+        let layer = *self
+            .layers
+            .iter()
+            .find(|(_, ranges)| {
+                ranges
+                    .get_key_value(range)
+                    .is_some_and(|(mapped, entry)| mapped.1 == range.1 && entry.device.ptr_eq(dev))
+            })?
+            .0;
+        let ranges = self.layers.get_mut(&layer)?;
+        let entry = ranges.remove(range);
+        if ranges.is_empty() {
+            self.layers.remove(&layer);
+        }
+        entry
+    }
+
+    /// Insert an alias, or report `Error::Overlap` if it overlaps another alias.
+    /// Aliases wrapping past the end of the address space are rejected with
+    /// `Error::Oversize`.
+    pub fn insert_alias(&mut self, range: Range, entry: BusEntry) -> Result<()> {
+        if !range.fits() {
+            return Err(Error::Oversize);
+        }
+        if overlapping(&self.aliases, &range).next().is_some() {
+            return Err(Error::Overlap);
+        }
+        self.aliases.insert(range, entry);
+        Ok(())
+    }
+
+    /// Remove the alias starting at `addr`.
+    pub fn remove_alias(&mut self, addr: GuestAddress) -> Option<BusEntry> {
+        self.aliases.remove(&Range(addr, 0))
+    }
+
+    /// Remove all the aliases routed to `dev`.
+    pub fn remove_aliases(&mut self, dev: &DeviceHandle) {
+        self.aliases.retain(|_, entry| !entry.device.ptr_eq(dev));
+    }
+
+    /// Return the number of bytes of the `len` bytes starting at `addr` that
+    /// are routed as `addr` is, with the entry mapping `addr` with the
+    /// highest priority, if any, and the offset of `addr` within the resource.
+    pub fn get_span(
+        &self,
+        addr: GuestAddress,
+        len: GuestUsize,
+    ) -> (GuestUsize, Option<(GuestUsize, &BusEntry)>) {
+        // Computed on 128 bits, ranges may end right at the top of the
+        // address space.
+        let mut end = u128::from(addr.0) + u128::from(len);
+        let span = |end: u128| (end - u128::from(addr.0)) as GuestUsize;
+        for ranges in iter::once(&self.aliases).chain(self.layers.values().rev()) {
+            // Ranges are ordered by their start address only, so the candidate
+            // is the last range starting at or before `addr`.
+            if let Some((range, entry)) = ranges.range(..=Range(addr, 0)).next_back() {
+                let offset = addr.0 - (range.0).0;
+                if offset < range.1 {
+                    end = end.min(u128::from((range.0).0) + u128::from(range.1));
+                    return (span(end), Some((entry.offset + offset, entry)));
+                }
+            }
+            // The next range of a higher priority takes over from its start.
+            let after = (Bound::Excluded(Range(addr, 0)), Bound::Unbounded);
+            if let Some((range, _)) = ranges.range(after).next() {
+                end = end.min(u128::from((range.0).0));
+            }
+        }
+        (span(end), None)
+    }
+
+    /// Return true if `range` lies within a container range of `parent`.
+    pub fn in_container(&self, range: &Range, parent: &DeviceHandle) -> bool {
+        self.container_layer(range, parent).is_some()
+    }
+
+    // Return the layer of the container range of `parent` holding `range`.
+    fn container_layer(&self, range: &Range, parent: &DeviceHandle) -> Option<u32> {
+        self.layers.iter().rev().find_map(|(layer, ranges)| {
+            let (mapped, entry) = ranges.range(..=*range).next_back()?;
+            if entry.container && entry.device.ptr_eq(parent) && mapped.contains(range) {
+                Some(*layer)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+// Return the ranges of a layer overlapping `range`. They never overlap each
+// other, so only the ranges right before and after the start of `range` can.
+fn overlapping<'a>(
+    ranges: &'a BTreeMap<Range, BusEntry>,
+    range: &Range,
+) -> impl Iterator<Item = (&'a Range, &'a BusEntry)> {
+    let range = *range;
+    let before = ranges.range(..=range).next_back();
+    let after = ranges.range(range..).next();
+    before
+        .into_iter()
+        .chain(after)
+        .filter(move |(mapped, _)| mapped.overlaps(&range))
+}
+
+/// A consistent view of all the buses.
+#[derive(Clone, Default)]
+pub(crate) struct IoBuses {
+    /// Range mapping of each trapping address space.
+    buses: BTreeMap<IoType, Bus>,
+    /// Unclaimed accesses, shared by all the versions of the bus maps.
+    unclaimed: Arc<Mutex<Unclaimed>>,
+    /// Moved resources, shared by all the versions of the bus maps.
+    relocations: Arc<Mutex<Relocations>>,
+}
+
+#[derive(Default)]
+struct Relocations {
+    /// Resources the guest moved and not relocated yet, oldest first.
+    pending: Vec<(DeviceHandle, usize, GuestAddress)>,
+    /// Signaled whenever a resource gets queued.
+    event: Option<EventFd>,
+}
+
+// Both are bounded, as the guest controls the unclaimed accesses.
+#[derive(Default)]
+struct Unclaimed {
+    /// Number of accesses, by address space and address.
+    counts: BTreeMap<(IoType, GuestAddress), u64>,
+    /// Accesses recorded by `Fallback::Record` buses, oldest first.
+    log: VecDeque<UnclaimedAccess>,
+}
+
+impl IoBuses {
+    /// Return the bus handling `io_type`, if any range got mapped in it.
+    pub fn bus(&self, io_type: IoType) -> Option<&Bus> {
+        self.buses.get(&io_type)
+    }
+
+    /// Return the bus handling `io_type` for modification, if it traps.
+    pub fn bus_mut(&mut self, io_type: IoType) -> Option<&mut Bus> {
+        match io_type {
+            IoType::PhysicalMmio => None,
+            _ => Some(self.buses.entry(io_type).or_default()),
+        }
+    }
+
+    /// Remove the aliases routed to `dev` from all the buses.
+    pub fn remove_aliases(&mut self, dev: &DeviceHandle) {
+        for bus in self.buses.values_mut() {
+            bus.remove_aliases(dev);
+        }
+    }
+
+    /// Return the number of unclaimed accesses, by address space and address.
+    pub fn unclaimed_counts(&self) -> Vec<(IoType, GuestAddress, u64)> {
+        let unclaimed = self.unclaimed.lock().expect("Failed to acquire lock");
+        unclaimed
+            .counts
+            .iter()
+            .map(|((io_type, addr), count)| (*io_type, *addr, *count))
+            .collect()
+    }
+
+    /// Return and clear the recorded unclaimed accesses.
+    pub fn take_unclaimed_log(&self) -> Vec<UnclaimedAccess> {
+        let mut unclaimed = self.unclaimed.lock().expect("Failed to acquire lock");
+        unclaimed.log.drain(..).collect()
+    }
+
+    /// Set the eventfd signaled when the guest moves a resource.
+    pub fn set_relocation_event(&self, event: EventFd) {
+        let mut relocations = self.relocations.lock().expect("Failed to acquire lock");
+        relocations.event = Some(event);
+    }
+
+    /// Return and clear the resources the guest moved, oldest first.
+    pub fn take_moved_resources(&self) -> Vec<(DeviceHandle, usize, GuestAddress)> {
+        let mut relocations = self.relocations.lock().expect("Failed to acquire lock");
+        std::mem::take(&mut relocations.pending)
+    }
+
+    // Queue the resources `dev` reports as moved, a later move of the same
+    // resource replacing the earlier one.
+    fn queue_moved_resources(&self, dev: &DeviceHandle) {
+        let moved = dev.take_moved_resources();
+        if moved.is_empty() {
+            return;
+        }
+        let mut relocations = self.relocations.lock().expect("Failed to acquire lock");
+        for (res_index, addr) in moved {
+            relocations
+                .pending
+                .retain(|(d, idx, _)| !(d.ptr_eq(dev) && *idx == res_index));
+            relocations.pending.push((dev.clone(), res_index, addr));
+        }
+        if let Some(event) = relocations.event.as_ref() {
+            // The pending list is the source of truth, a failed signal only
+            // delays the relocation until the next one.
+            let _ = event.write(1);
+        }
+    }
+
+    // Count an unclaimed access, record it if its bus asks so, and return the
+    // fallback of the bus.
+    fn fallback(
+        &self,
+        addr: GuestAddress,
+        io_type: IoType,
+        size: usize,
+        data: Option<&[u8]>,
+    ) -> Option<&Fallback> {
+        let fallback = self.bus(io_type).map(|bus| &bus.fallback);
+        let mut unclaimed = self.unclaimed.lock().expect("Failed to acquire lock");
+        let key = (io_type, addr);
+        if let Some(count) = unclaimed.counts.get_mut(&key) {
+            *count = count.saturating_add(1);
+        } else if unclaimed.counts.len() < MAX_UNCLAIMED_ADDRESSES {
+            unclaimed.counts.insert(key, 1);
+        }
+        if let Some(Fallback::Record) = fallback {
+            if unclaimed.log.len() == MAX_UNCLAIMED_LOG {
+                unclaimed.log.pop_front();
+            }
+            unclaimed.log.push_back(UnclaimedAccess {
+                addr,
+                io_type,
+                size,
+                data: data.map(|data| data.to_vec()),
+            });
+        }
+        fallback
+    }
+
+    fn read_unclaimed(&self, addr: GuestAddress, data: &mut [u8], io_type: IoType) -> Result<()> {
+        match self.fallback(addr, io_type, data.len(), None) {
+            Some(Fallback::AllOnes) => data.iter_mut().for_each(|d| *d = 0xff),
+            Some(Fallback::Zeros) => data.iter_mut().for_each(|d| *d = 0),
+            Some(Fallback::Forward(dev)) => {
+                return dev
+                    .read(0, addr.0, data, io_type)
+                    .map_err(|cause| Error::DeviceAccess {
+                        name: dev.name(),
+                        addr,
+                        io_type,
+                        cause,
+                    })
+            }
+            _ => return Err(Error::NonExist),
+        }
+        Ok(())
+    }
+
+    fn write_unclaimed(&self, addr: GuestAddress, data: &[u8], io_type: IoType) -> Result<()> {
+        match self.fallback(addr, io_type, data.len(), Some(data)) {
+            Some(Fallback::AllOnes) | Some(Fallback::Zeros) => Ok(()),
+            Some(Fallback::Forward(dev)) => {
+                dev.write(0, addr.0, data, io_type)
+                    .map_err(|cause| Error::DeviceAccess {
+                        name: dev.name(),
+                        addr,
+                        io_type,
+                        cause,
+                    })
+            }
+            _ => Err(Error::NonExist),
+        }
+    }
+
+    // Split an access into the parts routed the same way, according to the
+    // span policy of its bus, and hand them to `access` with their address,
+    // bytes and entry, if any.
+    fn dispatch<'a, F>(
+        &'a self,
+        addr: GuestAddress,
+        len: usize,
+        io_type: IoType,
+        mut access: F,
+    ) -> Result<()>
+    where
+        F: FnMut(GuestAddress, ops::Range<usize>, Option<(GuestUsize, &'a BusEntry)>) -> Result<()>,
+    {
+        let bus = match self.bus(io_type) {
+            Some(bus) => bus,
+            None => return access(addr, 0..len, None),
+        };
+        let (span, mapped) = bus.get_span(addr, len as GuestUsize);
+        let span = span as usize;
+        if span >= len {
+            return access(addr, 0..len, mapped);
+        }
+        // Route every part before issuing any access.
+        let mut parts = vec![(addr, 0..span, mapped)];
+        match bus.span_policy {
+            SpanPolicy::Reject => {
+                return Err(Error::SpanningAccess {
+                    addr,
+                    size: len,
+                    io_type,
+                })
+            }
+            SpanPolicy::Clip => {
+                parts.push((GuestAddress(addr.0 + span as GuestUsize), span..len, None));
+            }
+            SpanPolicy::Split => {
+                let mut start = span;
+                while start < len {
+                    let addr = GuestAddress(addr.0 + start as GuestUsize);
+                    let (span, mapped) = bus.get_span(addr, (len - start) as GuestUsize);
+                    let end = start + span as usize;
+                    parts.push((addr, start..end, mapped));
+                    start = end;
+                }
+            }
+        }
+        // Fail before any part takes effect if the fallback rejects one, the
+        // unclaimed part still gets counted and recorded.
+        if let Fallback::Fail | Fallback::Record = bus.fallback {
+            if let Some(idx) = parts.iter().position(|(_, _, mapped)| mapped.is_none()) {
+                let (addr, bytes, mapped) = parts.swap_remove(idx);
+                return access(addr, bytes, mapped);
+            }
+        }
+        for (addr, bytes, mapped) in parts {
+            access(addr, bytes, mapped)?;
+        }
+        Ok(())
+    }
+
+    fn read(&self, addr: GuestAddress, data: &mut [u8], io_type: IoType) -> Result<()> {
+        self.dispatch(addr, data.len(), io_type, |addr, bytes, mapped| {
+            let data = &mut data[bytes];
+            let (offset, entry) = match mapped {
+                Some(mapped) => mapped,
+                None => return self.read_unclaimed(addr, data, io_type),
+            };
+            entry
+                .device
+                .read(entry.index, offset, data, io_type)
+                .map_err(|cause| Error::DeviceAccess {
+                    name: entry.device.name(),
+                    addr,
+                    io_type,
+                    cause,
+                })
+        })
+    }
+
+    fn write(&self, addr: GuestAddress, data: &[u8], io_type: IoType) -> Result<()> {
+        self.dispatch(addr, data.len(), io_type, |addr, bytes, mapped| {
+            let data = &data[bytes];
+            let (offset, entry) = match mapped {
+                Some(mapped) => mapped,
+                None => return self.write_unclaimed(addr, data, io_type),
+            };
+            // Doorbells not registered as ioeventfds by the VMM get signaled here.
+            let result = match entry.doorbells.iter().find(|d| d.matches(offset, data)) {
+                Some(doorbell) => doorbell
+                    .ring()
+                    .map_err(|e| DeviceError::Internal(e.to_string())),
+                None => entry.device.write(entry.index, offset, data, io_type),
+            };
+            if result.is_ok() && io_type == IoType::PciConfig {
+                self.queue_moved_resources(&entry.device);
+            }
+            result.map_err(|cause| Error::DeviceAccess {
+                name: entry.device.name(),
+                addr,
+                io_type,
+                cause,
+            })
+        })
+    }
+}
+
+/// Handle dispatching VM exits to the registered devices.
+///
+/// Clones share the same bus maps and observe every device registered or
+/// unregistered through the `DeviceManager` they were obtained from.
+#[derive(Clone, Default)]
+pub struct IoDispatcher {
+    buses: Arc<RwLock<Arc<IoBuses>>>,
+    /// Bus maps replaced by `publish()`, possibly still used by VM exits.
+    retired: Arc<Mutex<Vec<Weak<IoBuses>>>>,
+}
+
+impl IoDispatcher {
+    /// Return the current bus maps.
+    pub(crate) fn snapshot(&self) -> Arc<IoBuses> {
+        self.buses
+            .read()
+            .expect("Failed to acquire bus lock")
+            .clone()
+    }
+
+    /// Replace the bus maps, returning the previous ones.
+    pub(crate) fn publish(&self, buses: IoBuses) -> Arc<IoBuses> {
+        let mut current = self.buses.write().expect("Failed to acquire bus lock");
+        let old = std::mem::replace(&mut *current, Arc::new(buses));
+        let mut retired = self.retired.lock().expect("Failed to acquire lock");
+        retired.retain(|buses| buses.strong_count() > 0);
+        retired.push(Arc::downgrade(&old));
+        old
+    }
+
+    /// Wait for all the VM exits still dispatched through any bus maps
+    /// replaced so far to complete.
+    ///
+    /// Must not be called from a device access handler, nor while holding
+    /// bus maps returned by `publish()`.
+    pub(crate) fn drain(&self) {
+        loop {
+            {
+                let mut retired = self.retired.lock().expect("Failed to acquire lock");
+                retired.retain(|buses| buses.strong_count() > 0);
+                if retired.is_empty() {
+                    return;
+                }
+            }
+            thread::yield_now();
+        }
+    }
+
+    /// Handle a PIO/MMIO read VM exit.
+    ///
+    /// Figure out the device according to `addr` and hand over the handling to device
+    /// specific read function, with `addr` translated to a resource index and offset.
+    /// Accesses no device claims are handled by the `Fallback` of the address
+    /// space, and the ones crossing range boundaries by its `SpanPolicy`.
+    /// Return error if failed to get the device or if the device failed
+    /// to handle the access.
+    pub fn read(&self, addr: GuestAddress, data: &mut [u8], io_type: IoType) -> Result<()> {
+        self.snapshot().read(addr, data, io_type)
+    }
+
+    /// Handle a PIO/MMIO write VM exit.
+    ///
+    /// Figure out the device according to `addr` and hand over the handling to device
+    /// specific write function, with `addr` translated to a resource index and offset.
+    /// Accesses no device claims are handled by the `Fallback` of the address
+    /// space, and the ones crossing range boundaries by its `SpanPolicy`.
+    /// Return error if failed to get the device or if the device failed
+    /// to handle the access.
+    pub fn write(&self, addr: GuestAddress, data: &[u8], io_type: IoType) -> Result<()> {
+        self.snapshot().write(addr, data, io_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device;
+    use crate::device::{Device, IoResource, IrqResource};
+    use std::sync::Mutex;
+
+    struct OffsetDevice;
+
+    impl Device for OffsetDevice {
+        fn name(&self) -> String {
+            "offset".to_string()
+        }
+        fn read(
+            &mut self,
+            res_index: usize,
+            offset: GuestUsize,
+            data: &mut [u8],
+            _io_type: IoType,
+        ) -> device::Result<()> {
+            data[0] = res_index as u8;
+            data[1] = offset as u8;
+            Ok(())
+        }
+        fn write(
+            &mut self,
+            _res_index: usize,
+            _offset: GuestUsize,
+            _data: &[u8],
+            _io_type: IoType,
+        ) -> device::Result<()> {
+            Ok(())
+        }
+        fn set_resources(&mut self, _res: &[IoResource], _irq: Option<IrqResource>) {}
+    }
+
+    fn entry(index: usize) -> BusEntry {
+        BusEntry {
+            index,
+            offset: 0,
+            device: DeviceHandle::Exclusive(Arc::new(Mutex::new(OffsetDevice))),
+            doorbells: Vec::new(),
+            container: false,
+        }
+    }
+
+    #[test]
+    fn test_bus_lookup() {
+        let mut bus = Bus::default();
+        bus.insert(Range(GuestAddress(0x100), 0x10), entry(0), None)
+            .unwrap();
+        bus.insert(Range(GuestAddress(0x200), 0x10), entry(1), None)
+            .unwrap();
+        assert!(bus
+            .insert(Range(GuestAddress(0x200), 0x20), entry(2), None)
+            .is_err());
+        // Partial overlaps, on both sides, and containing ranges.
+        assert!(bus
+            .insert(Range(GuestAddress(0x1f8), 0x10), entry(2), None)
+            .is_err());
+        assert!(bus
+            .insert(Range(GuestAddress(0x20f), 0x10), entry(2), None)
+            .is_err());
+        assert!(bus
+            .insert(Range(GuestAddress(0x0), 0x1000), entry(2), None)
+            .is_err());
+        assert!(bus
+            .insert(Range(GuestAddress(0x204), 0x4), entry(2), None)
+            .is_err());
+        // Ranges must not wrap past the end of the address space.
+        match bus.insert(Range(GuestAddress(u64::MAX - 1), 0x10), entry(2), None) {
+            Err(Error::Oversize) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(bus
+            .insert(Range(GuestAddress(u64::MAX - 1), 0x2), entry(2), None)
+            .is_ok());
+        assert!(bus
+            .insert(Range(GuestAddress(u64::MAX), 0x1), entry(2), None)
+            .is_err());
+        bus.insert(Range(GuestAddress(0x110), 0xf0), entry(2), None)
+            .unwrap();
+
+        assert!(bus.get_span(GuestAddress(0xff), 1).1.is_none());
+        assert_eq!(bus.get_span(GuestAddress(0x100), 1).1.unwrap().0, 0);
+        assert_eq!(bus.get_span(GuestAddress(0x10f), 1).1.unwrap().0, 0xf);
+        assert_eq!(bus.get_span(GuestAddress(0x110), 1).1.unwrap().1.index, 2);
+        let (offset, mapped) = bus.get_span(GuestAddress(0x204), 1).1.unwrap();
+        assert_eq!((offset, mapped.index), (4, 1));
+        assert!(bus.get_span(GuestAddress(0x210), 1).1.is_none());
+
+        let dev = bus
+            .get_span(GuestAddress(0x204), 1)
+            .1
+            .unwrap()
+            .1
+            .device
+            .clone();
+        assert!(bus
+            .remove(&Range(GuestAddress(0x200), 0x10), &entry(1).device)
+            .is_none());
+        assert!(bus
+            .remove(&Range(GuestAddress(0x200), 0x10), &dev)
+            .is_some());
+        assert!(bus.get_span(GuestAddress(0x204), 1).1.is_none());
+    }
+
+    #[test]
+    fn test_bus_layers() {
+        let mut bus = Bus::default();
+        let mut hole = entry(0);
+        hole.container = true;
+        let host = hole.device.clone();
+        bus.insert(Range(GuestAddress(0x1000), 0x1000), hole, None)
+            .unwrap();
+
+        // Only the children of the bus device may sit within its container.
+        assert!(bus
+            .insert(Range(GuestAddress(0x1100), 0x100), entry(1), None)
+            .is_err());
+        let mut window = entry(1);
+        window.container = true;
+        let bridge = window.device.clone();
+        bus.insert(Range(GuestAddress(0x1000), 0x800), window, Some(&host))
+            .unwrap();
+        // Children must lie entirely within the container.
+        assert!(bus
+            .insert(Range(GuestAddress(0xf00), 0x200), entry(2), Some(&host))
+            .is_err());
+        assert!(bus
+            .insert(Range(GuestAddress(0x1700), 0x200), entry(2), Some(&bridge))
+            .is_err());
+        assert!(bus
+            .insert(Range(GuestAddress(0x1400), 0x100), entry(2), Some(&host))
+            .is_err());
+        bus.insert(Range(GuestAddress(0x1400), 0x100), entry(2), Some(&bridge))
+            .unwrap();
+        bus.insert(Range(GuestAddress(0x1800), 0x100), entry(3), Some(&host))
+            .unwrap();
+
+        assert_eq!(bus.get_span(GuestAddress(0x1000), 1).1.unwrap().1.index, 1);
+        let (offset, mapped) = bus.get_span(GuestAddress(0x1404), 1).1.unwrap();
+        assert_eq!((offset, mapped.index), (4, 2));
+        let (offset, mapped) = bus.get_span(GuestAddress(0x1804), 1).1.unwrap();
+        assert_eq!((offset, mapped.index), (4, 3));
+        let (offset, mapped) = bus.get_span(GuestAddress(0x1904), 1).1.unwrap();
+        assert_eq!((offset, mapped.index), (0x904, 0));
+
+        // Aliases take precedence, with the offset they route to.
+        let mut alias = entry(4);
+        alias.offset = 0x20;
+        bus.insert_alias(Range(GuestAddress(0x1400), 0x10), alias)
+            .unwrap();
+        assert!(bus
+            .insert_alias(Range(GuestAddress(0x1408), 0x10), entry(5))
+            .is_err());
+        let (offset, mapped) = bus.get_span(GuestAddress(0x1404), 1).1.unwrap();
+        assert_eq!((offset, mapped.index), (0x24, 4));
+        assert!(bus.remove_alias(GuestAddress(0x1400)).is_some());
+        assert_eq!(bus.get_span(GuestAddress(0x1404), 1).1.unwrap().1.index, 2);
+
+        // The container range is visible again once the child is removed.
+        assert!(bus
+            .remove(&Range(GuestAddress(0x1000), 0x800), &host)
+            .is_none());
+        assert!(bus
+            .remove(&Range(GuestAddress(0x1000), 0x800), &bridge)
+            .is_some());
+        assert_eq!(bus.get_span(GuestAddress(0x1000), 1).1.unwrap().1.index, 0);
+    }
+
+    #[test]
+    fn test_bus_spans() {
+        let mut bus = Bus::default();
+        let mut hole = entry(0);
+        hole.container = true;
+        let host = hole.device.clone();
+        bus.insert(Range(GuestAddress(0x1000), 0x1000), hole, None)
+            .unwrap();
+        bus.insert(Range(GuestAddress(0x1400), 0x100), entry(1), Some(&host))
+            .unwrap();
+        bus.insert(Range(GuestAddress(u64::MAX - 0xf), 0x10), entry(2), None)
+            .unwrap();
+        bus.insert_alias(Range(GuestAddress(0x1480), 0x10), entry(3))
+            .unwrap();
+
+        // Spans end with their range, or where a higher priority range starts.
+        assert_eq!(bus.get_span(GuestAddress(0x13f8), 4).0, 4);
+        let (span, mapped) = bus.get_span(GuestAddress(0x13f8), 0x10);
+        assert_eq!((span, mapped.unwrap().1.index), (8, 0));
+        let (span, mapped) = bus.get_span(GuestAddress(0x1400), 0x200);
+        assert_eq!((span, mapped.unwrap().1.index), (0x80, 1));
+        let (span, mapped) = bus.get_span(GuestAddress(0x1488), 0x10);
+        assert_eq!((span, mapped.unwrap().1.index), (8, 3));
+        let (span, mapped) = bus.get_span(GuestAddress(0x1490), 0x200);
+        assert_eq!((span, mapped.unwrap().1.index), (0x70, 1));
+        let (span, mapped) = bus.get_span(GuestAddress(0x1ffc), 8);
+        assert_eq!((span, mapped.unwrap().1.index), (4, 0));
+        let (span, mapped) = bus.get_span(GuestAddress(0xff8), 0x10);
+        assert_eq!(span, 8);
+        assert!(mapped.is_none());
+        let (span, mapped) = bus.get_span(GuestAddress(u64::MAX - 3), 8);
+        assert_eq!((span, mapped.unwrap().0), (4, 0xc));
+    }
+
+    #[test]
+    fn test_dispatcher_publish() {
+        let dispatcher = IoDispatcher::default();
+        let vcpu = dispatcher.clone();
+        let mut data = [0u8; 2];
+
+        assert!(vcpu
+            .read(GuestAddress(0x10), &mut data, IoType::Pio)
+            .is_err());
+
+        let mut buses = (*dispatcher.snapshot()).clone();
+        buses
+            .bus_mut(IoType::Pio)
+            .unwrap()
+            .insert(Range(GuestAddress(0x10), 0x8), entry(3), None)
+            .unwrap();
+        assert!(buses.bus_mut(IoType::PhysicalMmio).is_none());
+        let old = dispatcher.publish(buses);
+        assert!(old.bus(IoType::Pio).is_none());
+
+        vcpu.read(GuestAddress(0x12), &mut data, IoType::Pio)
+            .unwrap();
+        assert_eq!(data, [3, 2]);
+        assert!(vcpu
+            .read(GuestAddress(0x12), &mut data, IoType::Mmio)
+            .is_err());
+        assert!(vcpu
+            .read(GuestAddress(0x12), &mut data, IoType::Custom(0))
+            .is_err());
+    }
+
+    #[test]
+    fn test_dispatcher_drain() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let dispatcher = IoDispatcher::default();
+        let in_flight = dispatcher.snapshot();
+        let done = Arc::new(AtomicBool::new(false));
+        let exited = done.clone();
+        let vcpu = thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_millis(10));
+            exited.store(true, Ordering::SeqCst);
+            drop(in_flight);
+        });
+
+        // The VM exit still uses bus maps older than the last replaced ones.
+        dispatcher.publish(IoBuses::default());
+        dispatcher.publish(IoBuses::default());
+        dispatcher.drain();
+        assert!(done.load(Ordering::SeqCst));
+        vcpu.join().unwrap();
+    }
+}
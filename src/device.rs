@@ -2,28 +2,439 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 //! Handles routing to devices in an address space.
+use crate::acpi::{self, AcpiDevice};
+use crate::fdt::FdtProperty;
+use crate::interrupt::InterruptSourceGroup;
+use crate::ioevent::Doorbell;
+use crate::memory::MemoryBacking;
+use crate::snapshot::Snapshot;
+use std::ops;
+use std::result;
 use std::string::String;
 use std::sync::{Arc, Mutex};
 use vm_memory::{GuestAddress, GuestUsize};
 
+/// Error type reported by devices when handling an access.
+#[derive(Debug)]
+pub enum Error {
+    /// The access width is not supported by the device.
+    InvalidAccessWidth(usize),
+    /// The access targets a reserved register at the given offset.
+    ReservedRegister(GuestUsize),
+    /// The access targets a resource index the device does not handle.
+    InvalidResource(usize),
+    /// The device failed internally while handling the access.
+    Internal(String),
+}
+
+/// Simplify the `Result` type of device accesses.
+pub type Result<T> = result::Result<T, Error>;
+
 /// Trait for devices with basic functions.
 #[allow(unused_variables)]
 pub trait Device: Send {
     /// Get the device name.
     fn name(&self) -> String;
-    /// Read from the guest physical address `addr` to `data`.
-    fn read(&mut self, addr: GuestAddress, data: &mut [u8], io_type: IoType);
-    /// Write `data` to the guest physical address `addr`.
-    fn write(&mut self, addr: GuestAddress, data: &[u8], io_type: IoType);
+    /// Read from the resource `res_index` at `offset` to `data`.
+    ///
+    /// `res_index` is the index of the accessed resource in the set handed
+    /// over by `set_resources()`, and `offset` is relative to its base.
+    /// Return error if the device can not handle the access.
+    fn read(
+        &mut self,
+        res_index: usize,
+        offset: GuestUsize,
+        data: &mut [u8],
+        io_type: IoType,
+    ) -> Result<()>;
+    /// Write `data` to the resource `res_index` at `offset`.
+    ///
+    /// Return error if the device can not handle the access.
+    fn write(
+        &mut self,
+        res_index: usize,
+        offset: GuestUsize,
+        data: &[u8],
+        io_type: IoType,
+    ) -> Result<()>;
     /// Set the allocated resource to device.
     ///
     /// This will be called by DeviceManager::register_device() to set
     /// the allocated resource from the vm_allocator back to device.
     fn set_resources(&mut self, res: &[IoResource], irq: Option<IrqResource>);
+    /// Set the interrupt sources of the allocated IRQ resource.
+    ///
+    /// This will be called by DeviceManager::register_device() before
+    /// `set_resources()` if the VMM provided an `InterruptBackend`.
+    fn set_interrupt_group(&mut self, group: Arc<dyn InterruptSourceGroup>) {}
+    /// Return the registers whose writes only signal an eventfd.
+    ///
+    /// This will be called by DeviceManager::register_device() after
+    /// `set_resources()`. Matching writes do not reach `write()`.
+    fn doorbells(&self) -> Vec<Doorbell> {
+        Vec::new()
+    }
+    /// Return the host memory backing the `PhysicalMmio` resource `res_index`.
+    ///
+    /// This will be called by DeviceManager::register_device() after
+    /// `set_resources()`. Resources without backing are not mapped.
+    fn memory_backing(&self, res_index: usize) -> Option<MemoryBacking> {
+        None
+    }
+    /// Return true if the resource `res_index` of a bus device is a container
+    /// range, e.g. a PCI hole or a bridge window.
+    ///
+    /// The fixed address ranges of its children lying within it take
+    /// precedence over it and are not allocated from the `SystemAllocator`.
+    /// The device handles the accesses no child claims.
+    fn is_container(&self, res_index: usize) -> bool {
+        false
+    }
+    /// Return and forget the resources the guest moved, e.g. by reprogramming
+    /// a PCI BAR, as resource index and new address pairs.
+    ///
+    /// This will be called by the `IoDispatcher` after each write to the PCI
+    /// configuration space of the device, see
+    /// DeviceManager::relocate_moved_resources().
+    fn take_moved_resources(&mut self) -> Vec<(usize, GuestAddress)> {
+        Vec::new()
+    }
+    /// Return true for virtio-mmio devices, declared on the guest kernel
+    /// command line by DeviceManager::virtio_mmio_cmdline().
+    fn is_virtio_mmio(&self) -> bool {
+        false
+    }
+    /// Return the device specific properties of its device tree node, such as
+    /// `compatible`.
+    ///
+    /// This will be called by DeviceManager::write_fdt(), after the `reg` and
+    /// `interrupts` properties got written.
+    fn fdt_properties(&self) -> Vec<FdtProperty> {
+        Vec::new()
+    }
+    /// Return the ACPI description of the device, if any.
+    ///
+    /// This will be called by DeviceManager::dsdt_aml(), madt_entries() and
+    /// mcfg_entries().
+    fn acpi(&self) -> Option<&dyn AcpiDevice> {
+        None
+    }
+    /// Notify a bus device that the device `name` got hot-plugged on it.
+    ///
+    /// `res` is the resource set allocated to the new child device.
+    fn child_added(&mut self, name: &str, res: &[IoResource]) {}
+    /// Notify a bus device that the device `name` got hot-unplugged from it.
+    fn child_removed(&mut self, name: &str) {}
+    /// Return the device specific state to save and restore, if any.
+    fn snapshot(&mut self) -> Option<&mut dyn Snapshot> {
+        None
+    }
+}
+
+/// Trait for devices handling accesses through a shared reference.
+///
+/// Unlike a [Device](trait.Device.html), a `SharedDevice` is not wrapped into
+/// a `Mutex` by the `DeviceManager`: accesses from several vCPUs reach it
+/// concurrently and the device protects its own state, e.g. per queue.
+#[allow(unused_variables)]
+pub trait SharedDevice: Send + Sync {
+    /// Get the device name.
+    fn name(&self) -> String;
+    /// Read from the resource `res_index` at `offset` to `data`.
+    ///
+    /// Same as [Device::read](trait.Device.html#tymethod.read).
+    fn read(
+        &self,
+        res_index: usize,
+        offset: GuestUsize,
+        data: &mut [u8],
+        io_type: IoType,
+    ) -> Result<()>;
+    /// Write `data` to the resource `res_index` at `offset`.
+    ///
+    /// Same as [Device::write](trait.Device.html#tymethod.write).
+    fn write(
+        &self,
+        res_index: usize,
+        offset: GuestUsize,
+        data: &[u8],
+        io_type: IoType,
+    ) -> Result<()>;
+    /// Set the allocated resource to device.
+    ///
+    /// Same as [Device::set_resources](trait.Device.html#tymethod.set_resources).
+    fn set_resources(&self, res: &[IoResource], irq: Option<IrqResource>);
+    /// Set the interrupt sources of the allocated IRQ resource.
+    ///
+    /// Same as [Device::set_interrupt_group](trait.Device.html#method.set_interrupt_group).
+    fn set_interrupt_group(&self, group: Arc<dyn InterruptSourceGroup>) {}
+    /// Return the registers whose writes only signal an eventfd.
+    ///
+    /// Same as [Device::doorbells](trait.Device.html#method.doorbells).
+    fn doorbells(&self) -> Vec<Doorbell> {
+        Vec::new()
+    }
+    /// Return the host memory backing the `PhysicalMmio` resource `res_index`.
+    ///
+    /// Same as [Device::memory_backing](trait.Device.html#method.memory_backing).
+    fn memory_backing(&self, res_index: usize) -> Option<MemoryBacking> {
+        None
+    }
+    /// Return and forget the resources the guest moved.
+    ///
+    /// Same as [Device::take_moved_resources](trait.Device.html#method.take_moved_resources).
+    fn take_moved_resources(&self) -> Vec<(usize, GuestAddress)> {
+        Vec::new()
+    }
+    /// Return true for virtio-mmio devices.
+    ///
+    /// Same as [Device::is_virtio_mmio](trait.Device.html#method.is_virtio_mmio).
+    fn is_virtio_mmio(&self) -> bool {
+        false
+    }
+    /// Return the device specific properties of its device tree node.
+    ///
+    /// Same as [Device::fdt_properties](trait.Device.html#method.fdt_properties).
+    fn fdt_properties(&self) -> Vec<FdtProperty> {
+        Vec::new()
+    }
+    /// Return the ACPI description of the device, if any.
+    ///
+    /// Same as [Device::acpi](trait.Device.html#method.acpi).
+    fn acpi(&self) -> Option<&dyn AcpiDevice> {
+        None
+    }
+    /// Return the device specific state to save and restore, if any.
+    ///
+    /// The state is locked while it gets saved or restored.
+    fn snapshot(&self) -> Option<&Mutex<dyn Snapshot + Send>> {
+        None
+    }
+}
+
+/// Reference to a registered device, whichever trait it implements.
+#[derive(Clone)]
+pub enum DeviceHandle {
+    /// A device handling one access at a time.
+    Exclusive(Arc<Mutex<dyn Device>>),
+    /// A device handling concurrent accesses.
+    Shared(Arc<dyn SharedDevice>),
+}
+
+impl DeviceHandle {
+    /// Get the device name.
+    pub fn name(&self) -> String {
+        match self {
+            DeviceHandle::Exclusive(dev) => dev.lock().expect("Failed to acquire lock").name(),
+            DeviceHandle::Shared(dev) => dev.name(),
+        }
+    }
+
+    /// Hand over a read access to the device.
+    pub fn read(
+        &self,
+        res_index: usize,
+        offset: GuestUsize,
+        data: &mut [u8],
+        io_type: IoType,
+    ) -> Result<()> {
+        match self {
+            DeviceHandle::Exclusive(dev) => dev
+                .lock()
+                .expect("Failed to acquire device lock")
+                .read(res_index, offset, data, io_type),
+            DeviceHandle::Shared(dev) => dev.read(res_index, offset, data, io_type),
+        }
+    }
+
+    /// Hand over a write access to the device.
+    pub fn write(
+        &self,
+        res_index: usize,
+        offset: GuestUsize,
+        data: &[u8],
+        io_type: IoType,
+    ) -> Result<()> {
+        match self {
+            DeviceHandle::Exclusive(dev) => dev
+                .lock()
+                .expect("Failed to acquire device lock")
+                .write(res_index, offset, data, io_type),
+            DeviceHandle::Shared(dev) => dev.write(res_index, offset, data, io_type),
+        }
+    }
+
+    /// Set the allocated resource to device.
+    pub fn set_resources(&self, res: &[IoResource], irq: Option<IrqResource>) {
+        match self {
+            DeviceHandle::Exclusive(dev) => dev
+                .lock()
+                .expect("Failed to acquire lock")
+                .set_resources(res, irq),
+            DeviceHandle::Shared(dev) => dev.set_resources(res, irq),
+        }
+    }
+
+    /// Set the interrupt sources of the allocated IRQ resource.
+    pub fn set_interrupt_group(&self, group: Arc<dyn InterruptSourceGroup>) {
+        match self {
+            DeviceHandle::Exclusive(dev) => dev
+                .lock()
+                .expect("Failed to acquire lock")
+                .set_interrupt_group(group),
+            DeviceHandle::Shared(dev) => dev.set_interrupt_group(group),
+        }
+    }
+
+    /// Return the registers whose writes only signal an eventfd.
+    pub fn doorbells(&self) -> Vec<Doorbell> {
+        match self {
+            DeviceHandle::Exclusive(dev) => dev.lock().expect("Failed to acquire lock").doorbells(),
+            DeviceHandle::Shared(dev) => dev.doorbells(),
+        }
+    }
+
+    /// Return the host memory backing the `PhysicalMmio` resource `res_index`.
+    pub fn memory_backing(&self, res_index: usize) -> Option<MemoryBacking> {
+        match self {
+            DeviceHandle::Exclusive(dev) => dev
+                .lock()
+                .expect("Failed to acquire lock")
+                .memory_backing(res_index),
+            DeviceHandle::Shared(dev) => dev.memory_backing(res_index),
+        }
+    }
+
+    /// Return true if the resource `res_index` is a container range.
+    pub fn is_container(&self, res_index: usize) -> bool {
+        match self {
+            DeviceHandle::Exclusive(dev) => dev
+                .lock()
+                .expect("Failed to acquire lock")
+                .is_container(res_index),
+            // Shared devices cannot be bus devices.
+            DeviceHandle::Shared(_) => false,
+        }
+    }
+
+    /// Return and forget the resources the guest moved.
+    pub fn take_moved_resources(&self) -> Vec<(usize, GuestAddress)> {
+        match self {
+            DeviceHandle::Exclusive(dev) => dev
+                .lock()
+                .expect("Failed to acquire lock")
+                .take_moved_resources(),
+            DeviceHandle::Shared(dev) => dev.take_moved_resources(),
+        }
+    }
+
+    /// Return true for virtio-mmio devices.
+    pub fn is_virtio_mmio(&self) -> bool {
+        match self {
+            DeviceHandle::Exclusive(dev) => {
+                dev.lock().expect("Failed to acquire lock").is_virtio_mmio()
+            }
+            DeviceHandle::Shared(dev) => dev.is_virtio_mmio(),
+        }
+    }
+
+    /// Return the device specific properties of its device tree node.
+    pub fn fdt_properties(&self) -> Vec<FdtProperty> {
+        match self {
+            DeviceHandle::Exclusive(dev) => {
+                dev.lock().expect("Failed to acquire lock").fdt_properties()
+            }
+            DeviceHandle::Shared(dev) => dev.fdt_properties(),
+        }
+    }
+
+    /// Return the AML device object of the device, built with its allocated
+    /// resources and IRQ, if it has an ACPI description.
+    pub fn acpi_aml(
+        &self,
+        res: &[IoResource],
+        irq: Option<IrqResource>,
+    ) -> Option<acpi::Result<Vec<u8>>> {
+        match self {
+            DeviceHandle::Exclusive(dev) => dev
+                .lock()
+                .expect("Failed to acquire lock")
+                .acpi()
+                .map(|desc| acpi::device_aml(desc, res, irq)),
+            DeviceHandle::Shared(dev) => dev.acpi().map(|desc| acpi::device_aml(desc, res, irq)),
+        }
+    }
+
+    /// Return the ACPI static table entry of the device, if any.
+    pub fn acpi_table_entry(&self) -> Option<acpi::AcpiTableEntry> {
+        match self {
+            DeviceHandle::Exclusive(dev) => dev
+                .lock()
+                .expect("Failed to acquire lock")
+                .acpi()
+                .and_then(|desc| desc.table_entry()),
+            DeviceHandle::Shared(dev) => dev.acpi().and_then(|desc| desc.table_entry()),
+        }
+    }
+
+    /// Save the device specific state, as a layout version and data pair.
+    pub fn save_state(&self) -> Option<(u32, Vec<u8>)> {
+        let save = |state: &dyn Snapshot| (state.version(), state.save());
+        match self {
+            DeviceHandle::Exclusive(dev) => dev
+                .lock()
+                .expect("Failed to acquire lock")
+                .snapshot()
+                .map(|state| save(state)),
+            DeviceHandle::Shared(dev) => dev
+                .snapshot()
+                .map(|state| save(&*state.lock().expect("Failed to acquire lock"))),
+        }
+    }
+
+    /// Restore the device specific state saved by `save_state()`.
+    pub fn restore_state(&self, version: u32, data: &[u8]) -> Result<()> {
+        let no_state = || Error::Internal("device has no state to restore".to_string());
+        match self {
+            DeviceHandle::Exclusive(dev) => dev
+                .lock()
+                .expect("Failed to acquire lock")
+                .snapshot()
+                .ok_or_else(no_state)?
+                .restore(version, data),
+            DeviceHandle::Shared(dev) => dev
+                .snapshot()
+                .ok_or_else(no_state)?
+                .lock()
+                .expect("Failed to acquire lock")
+                .restore(version, data),
+        }
+    }
+
+    /// Return true if both handles refer to the same device.
+    pub fn ptr_eq(&self, other: &DeviceHandle) -> bool {
+        match (self, other) {
+            (DeviceHandle::Exclusive(a), DeviceHandle::Exclusive(b)) => Arc::ptr_eq(a, b),
+            (DeviceHandle::Shared(a), DeviceHandle::Shared(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
 }
 
-/// IO Resource type.
-#[derive(Debug, Copy, Clone)]
+impl From<Arc<Mutex<dyn Device>>> for DeviceHandle {
+    fn from(dev: Arc<Mutex<dyn Device>>) -> Self {
+        DeviceHandle::Exclusive(dev)
+    }
+}
+
+impl From<Arc<dyn SharedDevice>> for DeviceHandle {
+    fn from(dev: Arc<dyn SharedDevice>) -> Self {
+        DeviceHandle::Shared(dev)
+    }
+}
+
+/// IO Resource type, i.e. the address space of the resource.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub enum IoType {
     /// Port I/O resource.
     Pio,
@@ -31,10 +442,17 @@ pub enum IoType {
     Mmio,
     /// Non-exit physically backed mmap IO
     PhysicalMmio,
+    /// PCI configuration space, addressed as `bdf << 12 | register` like
+    /// with ECAM. Addresses are not allocated, they must be given.
+    PciConfig,
+    /// Address space added with `DeviceManager::add_address_space()`, e.g.
+    /// a platform specific bus or the DMA space of a device behind an IOMMU.
+    /// Addresses are not allocated, they must be given.
+    Custom(u32),
 }
 
 /// Device resource information.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct IoResource {
     /// Resource address.
     pub addr: Option<GuestAddress>,
@@ -55,34 +473,99 @@ impl IoResource {
     }
 }
 
-/// Legacy interrupt resource.
-pub struct IrqResource(pub Option<u32>);
+/// Interrupt resource.
+///
+/// When requesting a resource, `None` and the vector count let the
+/// `DeviceManager` allocate interrupt numbers, while `Some` asks for fixed
+/// ones, e.g. IRQ 4 for a legacy UART. The resource handed back through
+/// `set_resources()` carries the allocated ones.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IrqResource {
+    /// Legacy interrupt line.
+    Legacy(Option<u32>),
+    /// Level triggered legacy interrupt line, shared with other devices, e.g.
+    /// PCI INTx. `None` picks the least used line set aside with
+    /// `SystemAllocator::reserve_shared_irqs()`.
+    SharedLegacy(Option<u32>),
+    /// MSI vectors, using consecutive interrupt numbers from `base`.
+    Msi {
+        /// First interrupt number.
+        base: Option<u32>,
+        /// Number of vectors, a power of two up to 32.
+        count: u32,
+    },
+    /// MSI-X vectors, using consecutive interrupt numbers from `base`.
+    MsiX {
+        /// First interrupt number.
+        base: Option<u32>,
+        /// Number of vectors, up to 2048.
+        count: u32,
+    },
+}
+
+impl IrqResource {
+    /// Return the allocated interrupt numbers, none if not allocated yet.
+    pub fn irqs(&self) -> ops::Range<u32> {
+        match *self {
+            IrqResource::Legacy(Some(irq)) | IrqResource::SharedLegacy(Some(irq)) => {
+                irq..irq.saturating_add(1)
+            }
+            IrqResource::Msi {
+                base: Some(base),
+                count,
+            }
+            | IrqResource::MsiX {
+                base: Some(base),
+                count,
+            } => base..base.saturating_add(count),
+            _ => 0..0,
+        }
+    }
+}
 
 /// Storing Device information and for topology managing by name.
 pub struct DeviceDescriptor {
     /// Device name.
     pub name: String,
     /// The device to descript.
-    pub device: Arc<Mutex<dyn Device>>,
+    pub device: DeviceHandle,
     /// The parent bus of this device.
     pub parent_bus: Option<Arc<Mutex<dyn Device>>>,
     /// Device resource set.
     pub resource: Vec<IoResource>,
+    /// Device interrupt, if any.
+    pub irq: Option<IrqResource>,
+    /// Device doorbells.
+    pub doorbells: Vec<Doorbell>,
+    /// Mapped `PhysicalMmio` resources, by resource index.
+    pub memory: Vec<(usize, MemoryBacking)>,
 }
 
 impl DeviceDescriptor {
     /// Create a descriptor for one device.
     pub fn new(
         name: String,
-        dev: Arc<Mutex<dyn Device>>,
+        dev: DeviceHandle,
         parent_bus: Option<Arc<Mutex<dyn Device>>>,
         resource: Vec<IoResource>,
+        irq: Option<IrqResource>,
     ) -> Self {
         DeviceDescriptor {
             name,
             device: dev,
             parent_bus,
             resource,
+            irq,
+            doorbells: Vec::new(),
+            memory: Vec::new(),
+        }
+    }
+
+    /// Return true if the device described by `bus` is the parent bus of this device.
+    pub fn is_child_of(&self, bus: &DeviceDescriptor) -> bool {
+        match (&self.parent_bus, &bus.device) {
+            (Some(parent), DeviceHandle::Exclusive(dev)) => Arc::ptr_eq(parent, dev),
+            _ => false,
         }
     }
 }
@@ -9,20 +9,70 @@
 //! VM IO exit handling.
 
 extern crate vm_allocator;
+extern crate vmm_sys_util;
 
 use self::vm_allocator::SystemAllocator;
-use crate::device::*;
+use self::vmm_sys_util::eventfd::EventFd;
+use crate::acpi::{self, AcpiTableEntry};
+use crate::bus::{Bus, BusEntry, Fallback, IoBuses, IoDispatcher, SpanPolicy, UnclaimedAccess};
+use crate::device::{Error as DeviceError, *};
+use crate::fdt::{self, FdtIrqCells, FdtProperty, FdtWriter};
+use crate::interrupt::{self, InterruptBackend, InterruptSourceGroup, IrqLine, LegacyIrq};
+use crate::ioevent::{Doorbell, IoEvent};
+use crate::memory::{MemoryBacking, MemoryMapper};
+use crate::snapshot::{DeviceManagerState, DeviceState};
 use std::cmp::{Ord, Ordering, PartialEq, PartialOrd};
-use std::collections::btree_map::BTreeMap;
 use std::collections::HashMap;
+use std::io;
 use std::result;
 use std::sync::{Arc, Mutex};
 use vm_memory::{GuestAddress, GuestUsize};
 
 /// Guest physical address and size pair to describe a range.
+///
+/// Ranges compare by their start address only, use `overlaps()` to compare
+/// their extent.
 #[derive(Eq, Debug, Copy, Clone)]
 pub struct Range(pub GuestAddress, pub GuestUsize);
 
+impl Range {
+    /// Return true if both ranges share at least one address, or start at the
+    /// same address.
+    pub fn overlaps(&self, other: &Range) -> bool {
+        if self.0 == other.0 {
+            return true;
+        }
+        if self.1 == 0 || other.1 == 0 {
+            return false;
+        }
+        (self.0).0 <= other.last() && (other.0).0 <= self.last()
+    }
+
+    /// Return true if the range does not wrap past the end of the address
+    /// space.
+    pub fn fits(&self) -> bool {
+        self.1 == 0 || (self.0).0.checked_add(self.1 - 1).is_some()
+    }
+
+    /// Return true if `other` lies within this range.
+    pub fn contains(&self, other: &Range) -> bool {
+        if self.1 == 0 {
+            return false;
+        }
+        let last = if other.1 == 0 {
+            (other.0).0
+        } else {
+            other.last()
+        };
+        self.0 <= other.0 && last <= self.last()
+    }
+
+    // Last address of a non empty range, clamped to the address space.
+    fn last(&self) -> u64 {
+        (self.0).0.saturating_add(self.1 - 1)
+    }
+}
+
 impl PartialEq for Range {
     fn eq(&self, other: &Range) -> bool {
         self.0 == other.0
@@ -37,7 +87,7 @@ impl Ord for Range {
 
 impl PartialOrd for Range {
     fn partial_cmp(&self, other: &Range) -> Option<Ordering> {
-        self.0.partial_cmp(&other.0)
+        Some(self.cmp(other))
     }
 }
 
@@ -50,12 +100,57 @@ pub enum Error {
     Oversize,
     /// PIO address is none.
     NonePIOAddress,
+    /// Address is none in an address space without allocation.
+    NoneAddress,
+    /// The address space was not added to the `DeviceManager`.
+    InvalidAddressSpace,
     /// The insertion failed because device already exists.
     Exist,
     /// The removing fails because the device doesn't exist.
     NonExist,
     /// IRQ allocated failed.
     AllocateIrq,
+    /// The requested IRQ is already allocated.
+    IrqConflict(u32),
+    /// The removing fails because the bus still has child devices.
+    ChildrenExist,
+    /// The saved state is malformed or does not match the restored devices.
+    InvalidSnapshot,
+    /// Mapping or unmapping the memory of a device failed.
+    MapMemory {
+        /// Name of the device.
+        name: String,
+        /// Error reported by the `MemoryMapper`.
+        cause: io::Error,
+    },
+    /// A device failed to restore its state.
+    RestoreDevice {
+        /// Name of the device.
+        name: String,
+        /// Error reported by the device.
+        cause: DeviceError,
+    },
+    /// The access spans several ranges, or past the end of a range, on a bus
+    /// rejecting such accesses.
+    SpanningAccess {
+        /// Guest address of the access.
+        addr: GuestAddress,
+        /// Size of the access, in bytes.
+        size: usize,
+        /// Type of the access.
+        io_type: IoType,
+    },
+    /// The device failed to handle an IO access.
+    DeviceAccess {
+        /// Name of the device handling the access.
+        name: String,
+        /// Guest address of the access.
+        addr: GuestAddress,
+        /// Type of the access.
+        io_type: IoType,
+        /// Error reported by the device.
+        cause: DeviceError,
+    },
 }
 
 /// Simplify the `Result` type.
@@ -67,10 +162,16 @@ pub struct DeviceManager<'a> {
     resource: &'a mut SystemAllocator,
     /// Devices information mapped by name.
     devices: HashMap<String, DeviceDescriptor>,
-    /// Range mapping for VM exit mmio operations.
-    mmio_bus: BTreeMap<Range, Arc<Mutex<dyn Device>>>,
-    /// Range mapping for VM exit pio operations.
-    pio_bus: BTreeMap<Range, Arc<Mutex<dyn Device>>>,
+    /// Range mappings for VM exit mmio and pio operations.
+    io: IoDispatcher,
+    /// Backend delivering the device interrupts, if any.
+    interrupts: Option<Arc<dyn InterruptBackend>>,
+    /// Shared interrupt lines, by interrupt number.
+    irq_lines: HashMap<u32, Arc<IrqLine>>,
+    /// Mapper of the device memory, if any.
+    memory_mapper: Option<Arc<dyn MemoryMapper>>,
+    /// Names of the custom address spaces, by `IoType::Custom` index.
+    address_spaces: Vec<String>,
 }
 
 impl<'a> DeviceManager<'a> {
@@ -80,9 +181,133 @@ impl<'a> DeviceManager<'a> {
         DeviceManager {
             resource,
             devices: HashMap::new(),
-            mmio_bus: BTreeMap::new(),
-            pio_bus: BTreeMap::new(),
+            io: IoDispatcher::default(),
+            interrupts: None,
+            irq_lines: HashMap::new(),
+            memory_mapper: None,
+            address_spaces: Vec::new(),
+        }
+    }
+
+    /// Add the custom address space `name`, returning its `IoType`.
+    ///
+    /// Devices register ranges at fixed addresses in it, which get dispatched
+    /// by `read()` and `write()` with the returned `IoType`.
+    pub fn add_address_space(&mut self, name: &str) -> Result<IoType> {
+        if self.address_space(name).is_some() {
+            return Err(Error::Exist);
+        }
+        self.address_spaces.push(name.to_string());
+        Ok(IoType::Custom(self.address_spaces.len() as u32 - 1))
+    }
+
+    /// Return the `IoType` of the custom address space `name`.
+    pub fn address_space(&self, name: &str) -> Option<IoType> {
+        self.address_spaces
+            .iter()
+            .position(|n| n == name)
+            .map(|id| IoType::Custom(id as u32))
+    }
+
+    // Return the bus of `io_type` in `buses`, if it is a trapping address space.
+    fn trapping_bus<'b>(&self, buses: &'b mut IoBuses, io_type: IoType) -> Option<&'b mut Bus> {
+        match io_type {
+            IoType::Custom(id) if id as usize >= self.address_spaces.len() => None,
+            _ => buses.bus_mut(io_type),
+        }
+    }
+
+    /// Set how the accesses no device claims in the address space `io_type`
+    /// are handled, `Fallback::Fail` by default.
+    pub fn set_fallback(&mut self, io_type: IoType, fallback: Fallback) -> Result<()> {
+        let mut buses = (*self.io.snapshot()).clone();
+        self.trapping_bus(&mut buses, io_type)
+            .ok_or(Error::InvalidAddressSpace)?
+            .fallback = fallback;
+        self.io.publish(buses);
+        Ok(())
+    }
+
+    /// Set how the accesses spanning several ranges of the address space
+    /// `io_type`, or past the end of a range, are handled,
+    /// `SpanPolicy::Reject` by default.
+    pub fn set_span_policy(&mut self, io_type: IoType, policy: SpanPolicy) -> Result<()> {
+        let mut buses = (*self.io.snapshot()).clone();
+        self.trapping_bus(&mut buses, io_type)
+            .ok_or(Error::InvalidAddressSpace)?
+            .span_policy = policy;
+        self.io.publish(buses);
+        Ok(())
+    }
+
+    /// Return the number of accesses no device claimed, by address space and
+    /// address, e.g. to find out which legacy ports a guest probes.
+    ///
+    /// Only the first `MAX_UNCLAIMED_ADDRESSES` addresses get counted, and
+    /// the counts saturate.
+    pub fn unclaimed_accesses(&self) -> Vec<(IoType, GuestAddress, u64)> {
+        self.io.snapshot().unclaimed_counts()
+    }
+
+    /// Return and clear the accesses recorded by the `Fallback::Record`
+    /// address spaces, oldest first.
+    ///
+    /// Only the last `MAX_UNCLAIMED_LOG` accesses are kept.
+    pub fn take_unclaimed_log(&self) -> Vec<UnclaimedAccess> {
+        self.io.snapshot().take_unclaimed_log()
+    }
+
+    /// Set the mapper of the `PhysicalMmio` resources of the devices
+    /// registered from now on.
+    ///
+    /// Each resource a device provides a `MemoryBacking` for gets mapped at
+    /// its allocated address until the device is unregistered.
+    pub fn set_memory_mapper(&mut self, mapper: Arc<dyn MemoryMapper>) {
+        self.memory_mapper = Some(mapper);
+    }
+
+    /// Set the backend delivering the interrupts of the devices registered
+    /// from now on.
+    ///
+    /// Each of them gets the interrupt sources of its IRQ resource through
+    /// `set_interrupt_group()`. Fails with `Error::Exist` while devices share
+    /// a legacy line, as the devices registered later would not share its
+    /// level.
+    pub fn set_interrupt_backend(&mut self, backend: Arc<dyn InterruptBackend>) -> Result<()> {
+        if !self.irq_lines.is_empty() {
+            return Err(Error::Exist);
         }
+        self.interrupts = Some(backend);
+        Ok(())
+    }
+
+    fn set_interrupt_group(&mut self, dev: &DeviceHandle, irq: Option<IrqResource>) {
+        let (irq, backend) = match (irq, self.interrupts.as_ref()) {
+            (Some(irq), Some(backend)) => (irq, backend),
+            _ => return,
+        };
+        let group = match irq {
+            // Devices sharing a line also share its level.
+            IrqResource::SharedLegacy(Some(irq)) => {
+                let line = self
+                    .irq_lines
+                    .entry(irq)
+                    .or_insert_with(|| Arc::new(IrqLine::new(irq, backend.clone())));
+                Some(Arc::new(LegacyIrq::new(line.clone())) as Arc<dyn InterruptSourceGroup>)
+            }
+            _ => interrupt::interrupt_group(&irq, backend.clone()),
+        };
+        if let Some(group) = group {
+            dev.set_interrupt_group(group);
+        }
+    }
+
+    /// Return a handle dispatching VM exits to the registered devices.
+    ///
+    /// The handle can be cloned and moved to vCPU threads. It keeps routing
+    /// accesses to the devices registered later on through this `DeviceManager`.
+    pub fn io_dispatcher(&self) -> IoDispatcher {
+        self.io.clone()
     }
 
     fn insert(&mut self, dev: DeviceDescriptor) -> Result<()> {
@@ -94,29 +319,69 @@ impl<'a> DeviceManager<'a> {
         Ok(())
     }
 
-    fn remove(&mut self, name: String) -> Option<DeviceDescriptor> {
+    fn remove(&mut self, dev: &DeviceHandle) -> Option<DeviceDescriptor> {
+        // Look the device up by identity, its name may have changed since it
+        // got registered.
+        let name = self
+            .devices
+            .values()
+            .find(|descriptor| descriptor.device.ptr_eq(dev))?
+            .name
+            .clone();
         self.devices.remove(&name)
     }
 
     fn device_descriptor(
         &self,
-        dev: Arc<Mutex<dyn Device>>,
+        dev: DeviceHandle,
         parent_bus: Option<Arc<Mutex<dyn Device>>>,
         resource: Vec<IoResource>,
+        irq: Option<IrqResource>,
     ) -> DeviceDescriptor {
-        let name = dev.lock().expect("Failed to require lock").name();
-        DeviceDescriptor::new(name, dev.clone(), parent_bus, resource)
+        let name = dev.name();
+        DeviceDescriptor::new(name, dev, parent_bus, resource, irq)
+    }
+
+    // Return true if `res` lies within a container range of `parent_bus`,
+    // which already holds its addresses.
+    fn sub_allocated(&self, parent_bus: Option<&Arc<Mutex<dyn Device>>>, res: &IoResource) -> bool {
+        let (parent, addr) = match (parent_bus, res.addr) {
+            (Some(parent), Some(addr)) => (DeviceHandle::Exclusive(parent.clone()), addr),
+            _ => return false,
+        };
+        self.io
+            .snapshot()
+            .bus(res.res_type)
+            .is_some_and(|bus| bus.in_container(&Range(addr, res.size), &parent))
     }
 
-    fn allocate_resources(&mut self, resource: &mut Vec<IoResource>) -> Result<()> {
+    fn allocate_resources(
+        &mut self,
+        parent_bus: Option<&Arc<Mutex<dyn Device>>>,
+        resource: &mut Vec<IoResource>,
+    ) -> Result<()> {
+        for res in resource.iter() {
+            match res.res_type {
+                IoType::Custom(id) if id as usize >= self.address_spaces.len() => {
+                    return Err(Error::InvalidAddressSpace);
+                }
+                IoType::PciConfig | IoType::Custom(_) if res.addr.is_none() => {
+                    return Err(Error::NoneAddress);
+                }
+                IoType::Pio if res.addr.is_none() => return Err(Error::NonePIOAddress),
+                _ => (),
+            }
+        }
+
         let mut alloc_idx = 0;
 
         for res in resource.iter_mut() {
+            if self.sub_allocated(parent_bus, res) {
+                alloc_idx += 1;
+                continue;
+            }
             match res.res_type {
                 IoType::Pio => {
-                    if res.addr.is_none() {
-                        return Err(Error::NonePIOAddress);
-                    }
                     res.addr = self
                         .resource
                         .allocate_io_addresses(res.addr.unwrap(), res.size);
@@ -124,6 +389,8 @@ impl<'a> DeviceManager<'a> {
                 IoType::PhysicalMmio | IoType::Mmio => {
                     res.addr = self.resource.allocate_mmio_addresses(res.addr, res.size)
                 }
+                // Only the bus maps track these address spaces.
+                IoType::PciConfig | IoType::Custom(_) => (),
             }
             if res.addr.is_none() {
                 // Failed to allocate resource.
@@ -138,53 +405,205 @@ impl<'a> DeviceManager<'a> {
         }
 
         // Failed and free the previous resource.
-        self.free_resources(&resource[0..alloc_idx]);
+        self.free_resources(parent_bus, &resource[0..alloc_idx]);
         Err(Error::Overlap)
     }
 
-    fn free_resources(&mut self, resource: &[IoResource]) {
+    fn free_resources(
+        &mut self,
+        parent_bus: Option<&Arc<Mutex<dyn Device>>>,
+        resource: &[IoResource],
+    ) {
         for res in resource.iter() {
+            if self.sub_allocated(parent_bus, res) {
+                continue;
+            }
             match res.res_type {
                 IoType::Pio => self.resource.free_io_addresses(res.addr.unwrap(), res.size),
                 IoType::PhysicalMmio | IoType::Mmio => self
                     .resource
                     .free_mmio_addresses(res.addr.unwrap(), res.size),
+                IoType::PciConfig | IoType::Custom(_) => (),
+            }
+        }
+    }
+
+    fn allocate_irq(&mut self, irq_resource: IrqResource) -> Result<IrqResource> {
+        let (base, count) = match irq_resource {
+            IrqResource::Legacy(base) => (base, 1),
+            IrqResource::Msi { base, count } if count.is_power_of_two() && count <= 32 => {
+                (base, count)
+            }
+            IrqResource::MsiX { base, count } if count > 0 && count <= 2048 => (base, count),
+            IrqResource::SharedLegacy(Some(irq)) => {
+                self.resource
+                    .reserve_shared_irq(irq)
+                    .ok_or(Error::IrqConflict(irq))?;
+                return Ok(irq_resource);
+            }
+            IrqResource::SharedLegacy(None) => {
+                let irq = self
+                    .resource
+                    .allocate_shared_irq()
+                    .ok_or(Error::AllocateIrq)?;
+                return Ok(IrqResource::SharedLegacy(Some(irq)));
+            }
+            _ => return Err(Error::AllocateIrq),
+        };
+        let base = match base {
+            // Fixed interrupt numbers, e.g. of legacy devices.
+            Some(base) => self
+                .resource
+                .reserve_irqs(base, count)
+                .ok_or(Error::IrqConflict(base))?,
+            None => self
+                .resource
+                .allocate_irqs(count)
+                .ok_or(Error::AllocateIrq)?,
+        };
+        Ok(match irq_resource {
+            IrqResource::Legacy(_) => IrqResource::Legacy(Some(base)),
+            IrqResource::SharedLegacy(_) => IrqResource::SharedLegacy(Some(base)),
+            IrqResource::Msi { count, .. } => IrqResource::Msi {
+                base: Some(base),
+                count,
+            },
+            IrqResource::MsiX { count, .. } => IrqResource::MsiX {
+                base: Some(base),
+                count,
+            },
+        })
+    }
+
+    fn free_irq(&mut self, irq: Option<IrqResource>) {
+        for irq in irq.iter().flat_map(|irq| irq.irqs()) {
+            self.resource.free_irq(irq);
+        }
+        // A shared line goes away with the last registered device on it.
+        if let Some(IrqResource::SharedLegacy(Some(line))) = irq {
+            if !self.devices.values().any(|d| d.irq == irq) {
+                self.irq_lines.remove(&line);
+            }
+        }
+    }
+
+    fn map_memory(
+        &self,
+        dev: &DeviceHandle,
+        resource: &[IoResource],
+    ) -> Result<Vec<(usize, MemoryBacking)>> {
+        let mapper = match self.memory_mapper {
+            Some(ref mapper) => mapper,
+            None => return Ok(Vec::new()),
+        };
+        let mut memory: Vec<(usize, MemoryBacking)> = Vec::new();
+        for (idx, res) in resource.iter().enumerate() {
+            if res.res_type != IoType::PhysicalMmio {
+                continue;
+            }
+            let backing = match dev.memory_backing(idx) {
+                Some(backing) => backing,
+                None => continue,
+            };
+            if let Err(cause) = mapper.map(res.addr.unwrap(), res.size, &backing) {
+                // Undo the mappings done so far.
+                for (idx, _) in memory {
+                    let res = &resource[idx];
+                    let _ = mapper.unmap(res.addr.unwrap(), res.size);
+                }
+                return Err(Error::MapMemory {
+                    name: dev.name(),
+                    cause,
+                });
+            }
+            memory.push((idx, backing));
+        }
+        Ok(memory)
+    }
+
+    fn unmap_memory(
+        &self,
+        name: &str,
+        resource: &[IoResource],
+        memory: &[(usize, MemoryBacking)],
+    ) -> Result<()> {
+        let mapper = match self.memory_mapper {
+            Some(ref mapper) => mapper,
+            None => return Ok(()),
+        };
+        let mut result = Ok(());
+        for (idx, _) in memory.iter() {
+            let res = &resource[*idx];
+            if let Err(cause) = mapper.unmap(res.addr.unwrap(), res.size) {
+                result = Err(Error::MapMemory {
+                    name: name.to_string(),
+                    cause,
+                });
+            }
+        }
+        result
+    }
+
+    fn map_resource(
+        buses: &mut IoBuses,
+        dev: &DeviceHandle,
+        parent_bus: Option<&Arc<Mutex<dyn Device>>>,
+        resource: &[IoResource],
+        doorbells: &[Doorbell],
+    ) -> Result<()> {
+        let parent = parent_bus.map(|bus| DeviceHandle::Exclusive(bus.clone()));
+        for (idx, res) in resource.iter().enumerate() {
+            if let Some(bus) = buses.bus_mut(res.res_type) {
+                let entry = BusEntry {
+                    index: idx,
+                    offset: 0,
+                    device: dev.clone(),
+                    doorbells: doorbells
+                        .iter()
+                        .filter(|d| d.res_index == idx)
+                        .cloned()
+                        .collect(),
+                    container: dev.is_container(idx),
+                };
+                bus.insert(Range(res.addr.unwrap(), res.size), entry, parent.as_ref())?;
             }
         }
+        Ok(())
     }
 
     fn register_resource(
         &mut self,
-        dev: Arc<Mutex<dyn Device>>,
-        resource: &mut Vec<IoResource>,
+        dev: DeviceHandle,
+        parent_bus: Option<&Arc<Mutex<dyn Device>>>,
+        resource: &[IoResource],
+        doorbells: &[Doorbell],
     ) -> Result<()> {
-        for res in resource.iter() {
-            match res.res_type {
-                IoType::Pio => {
-                    if self
-                        .pio_bus
-                        .insert(Range(res.addr.unwrap(), res.size), dev.clone())
-                        .is_some()
-                    {
-                        return Err(Error::Overlap);
-                    }
-                }
-                IoType::Mmio => {
-                    if self
-                        .mmio_bus
-                        .insert(Range(res.addr.unwrap(), res.size), dev.clone())
-                        .is_some()
-                    {
-                        return Err(Error::Overlap);
-                    }
-                }
-                IoType::PhysicalMmio => continue,
-            };
+        // Ranges wrapping past the end of the address space cannot be
+        // mapped, whether they trap or not.
+        if resource
+            .iter()
+            .any(|res| !Range(res.addr.unwrap(), res.size).fits())
+        {
+            return Err(Error::Oversize);
         }
+        // Only publish the new bus maps once every range got inserted.
+        let mut buses = (*self.io.snapshot()).clone();
+        Self::map_resource(&mut buses, &dev, parent_bus, resource, doorbells)?;
+        self.io.publish(buses);
         Ok(())
     }
 
     /// Register a new device with its parent bus and resource request set.
+    ///
+    /// Ranges at fixed addresses lying within a container range of the parent
+    /// bus, see `Device::is_container()`, take precedence over it and are not
+    /// allocated from the `SystemAllocator`. Other ranges must not overlap any
+    /// mapped range.
+    ///
+    /// Registration is all or nothing: on failure, the allocated resources and
+    /// IRQ are freed, the buses are left untouched and `resource` holds the
+    /// original request again. The device may still have been handed the
+    /// resources through `set_resources()` and must not use them.
     pub fn register_device(
         &mut self,
         dev: Arc<Mutex<dyn Device>>,
@@ -192,199 +611,933 @@ impl<'a> DeviceManager<'a> {
         resource: &mut Vec<IoResource>,
         interrupt: Option<IrqResource>,
     ) -> Result<()> {
+        self.register(
+            DeviceHandle::Exclusive(dev),
+            parent_bus,
+            resource,
+            interrupt,
+        )
+    }
+
+    /// Register a new shared device with its parent bus and resource request set.
+    ///
+    /// Accesses to a `SharedDevice` are dispatched without any locking on the
+    /// `DeviceManager` side. Otherwise it behaves as `register_device()`.
+    pub fn register_shared_device(
+        &mut self,
+        dev: Arc<dyn SharedDevice>,
+        parent_bus: Option<Arc<Mutex<dyn Device>>>,
+        resource: &mut Vec<IoResource>,
+        interrupt: Option<IrqResource>,
+    ) -> Result<()> {
+        self.register(DeviceHandle::Shared(dev), parent_bus, resource, interrupt)
+    }
+
+    fn register(
+        &mut self,
+        dev: DeviceHandle,
+        parent_bus: Option<Arc<Mutex<dyn Device>>>,
+        resource: &mut Vec<IoResource>,
+        interrupt: Option<IrqResource>,
+    ) -> Result<()> {
+        // Check the name first, so that a colliding device is left untouched.
+        let name = dev.name();
+        if self.devices.contains_key(&name) {
+            return Err(Error::Exist);
+        }
+        let request = resource.clone();
+
         // Reserve resource
-        if let Err(e) = self.allocate_resources(resource) {
+        let parent = parent_bus.as_ref();
+        if let Err(e) = self.allocate_resources(parent, resource) {
+            *resource = request;
             return Err(e);
         }
+        let irq = match interrupt.map(|irq| self.allocate_irq(irq)).transpose() {
+            Ok(irq) => irq,
+            Err(e) => return Err(self.rollback(parent, resource, request, None, e)),
+        };
 
-        // Register device resource
-        if let Err(Error::Overlap) = self.register_resource(dev.clone(), resource) {
-            return Err(Error::Overlap);
-        }
+        // Set the interrupt sources and the allocated resource back
+        self.set_interrupt_group(&dev, irq);
+        dev.set_resources(resource, irq);
+        let doorbells = dev.doorbells();
+        let memory = match self.map_memory(&dev, resource) {
+            Ok(memory) => memory,
+            Err(e) => return Err(self.rollback(parent, resource, request, irq, e)),
+        };
 
-        match interrupt {
-            Some(IrqResource(irq)) => {
-                match irq {
-                    // TODO: Return Error when requesting a specified irq resource
-                    Some(_) => {
-                        return Err(Error::AllocateIrq);
-                    }
-                    // Allocate irq resource
-                    None => {
-                        // Set the allocated resource back
-                        dev.lock().expect("Failed to acquire lock.").set_resources(
-                            resource,
-                            Some(IrqResource(self.resource.allocate_irq())),
-                        );
-                    }
-                }
-            }
-            None => {
-                dev.lock()
-                    .expect("Failed to acquire lock.")
-                    .set_resources(resource, None);
-            }
+        // Register device resource, once the device knows about it.
+        if let Err(e) = self.register_resource(dev.clone(), parent, resource, &doorbells) {
+            let _ = self.unmap_memory(&name, resource, &memory);
+            return Err(self.rollback(parent, resource, request, irq, e));
         }
 
         // Insert bus/device to DeviceManager with parent bus
-        let descriptor = self.device_descriptor(dev, parent_bus, resource.to_vec());
+        let mut descriptor = self.device_descriptor(dev, parent_bus, resource.to_vec(), irq);
+        descriptor.doorbells = doorbells;
+        descriptor.memory = memory;
         self.insert(descriptor)
     }
 
+    // Undo a failed registration, freeing the allocated resource and irq and
+    // handing the original request back to the caller.
+    fn rollback(
+        &mut self,
+        parent_bus: Option<&Arc<Mutex<dyn Device>>>,
+        resource: &mut Vec<IoResource>,
+        request: Vec<IoResource>,
+        irq: Option<IrqResource>,
+        error: Error,
+    ) -> Error {
+        self.free_resources(parent_bus, resource);
+        self.free_irq(irq);
+        *resource = request;
+        error
+    }
+
     /// Unregister a device from `DeviceManager`.
+    ///
+    /// Bus devices can only be unregistered once all their children are.
     pub fn unregister_device(&mut self, dev: Arc<Mutex<dyn Device>>) -> Result<()> {
-        let name = dev.lock().expect("Failed to acquire lock").name();
+        self.unregister(DeviceHandle::Exclusive(dev))
+    }
+
+    /// Unregister a shared device from `DeviceManager`.
+    pub fn unregister_shared_device(&mut self, dev: Arc<dyn SharedDevice>) -> Result<()> {
+        self.unregister(DeviceHandle::Shared(dev))
+    }
+
+    fn unregister(&mut self, dev: DeviceHandle) -> Result<()> {
+        self.unregister_descriptor(&dev, false).map(|_| ())
+    }
+
+    fn unregister_descriptor(
+        &mut self,
+        dev: &DeviceHandle,
+        drain: bool,
+    ) -> Result<DeviceDescriptor> {
+        if let Some(descriptor) = self.devices.values().find(|d| d.device.ptr_eq(dev)) {
+            if !self.children_of(&descriptor.name).is_empty() {
+                return Err(Error::ChildrenExist);
+            }
+        }
 
-        if let Some(descriptor) = self.remove(name) {
+        if let Some(descriptor) = self.remove(dev) {
+            let mut buses = (*self.io.snapshot()).clone();
             for res in descriptor.resource.iter() {
-                if res.addr.is_some() {
-                    match res.res_type {
-                        IoType::Pio => self.pio_bus.remove(&Range(res.addr.unwrap(), res.size)),
-                        IoType::Mmio => self.mmio_bus.remove(&Range(res.addr.unwrap(), res.size)),
-                        IoType::PhysicalMmio => continue,
-                    };
+                if let (Some(addr), Some(bus)) = (res.addr, buses.bus_mut(res.res_type)) {
+                    bus.remove(&Range(addr, res.size), &descriptor.device);
                 }
             }
-            // Free the resource
-            self.free_resources(&descriptor.resource);
-            Ok(())
+            buses.remove_aliases(&descriptor.device);
+            self.io.publish(buses);
+            if drain {
+                self.io.drain();
+            }
+            // Unmap and free the resource, even if unmapping fails.
+            let unmapped =
+                self.unmap_memory(&descriptor.name, &descriptor.resource, &descriptor.memory);
+            self.free_resources(descriptor.parent_bus.as_ref(), &descriptor.resource);
+            self.free_irq(descriptor.irq);
+            unmapped.map(|_| descriptor)
         } else {
             Err(Error::NonExist)
         }
     }
 
-    fn first_before(
-        &self,
+    /// Hot-plug a device while the VM is running.
+    ///
+    /// The device is registered as with `register_device()`. Its ranges become
+    /// visible to all vCPUs at once, after the device got its resources, and the
+    /// parent bus is then notified through `Device::child_added()`.
+    pub fn hotplug_device(
+        &mut self,
+        dev: DeviceHandle,
+        parent_bus: Option<Arc<Mutex<dyn Device>>>,
+        resource: &mut Vec<IoResource>,
+        interrupt: Option<IrqResource>,
+    ) -> Result<()> {
+        let name = dev.name();
+        self.register(dev, parent_bus.clone(), resource, interrupt)?;
+        if let Some(bus) = parent_bus {
+            bus.lock()
+                .expect("Failed to acquire lock")
+                .child_added(&name, resource);
+        }
+        Ok(())
+    }
+
+    /// Hot-unplug a device while the VM is running.
+    ///
+    /// The device ranges are removed from all buses at once, then this waits for
+    /// the VM exits still being handled by the device to complete. Only then the
+    /// parent bus is notified through `Device::child_removed()` and the device
+    /// resources are returned to the `SystemAllocator` for reuse.
+    ///
+    /// Must not be called from a device access handler.
+    pub fn hot_unplug_device(&mut self, dev: DeviceHandle) -> Result<()> {
+        let descriptor = self.unregister_descriptor(&dev, true)?;
+        if let Some(bus) = descriptor.parent_bus {
+            bus.lock()
+                .expect("Failed to acquire lock")
+                .child_removed(&descriptor.name);
+        }
+        Ok(())
+    }
+
+    /// Move the resource `res_index` of a registered device to `addr`, e.g.
+    /// when the guest reprograms a PCI BAR.
+    ///
+    /// The new range is reserved from the `SystemAllocator`, unless it lies
+    /// within a container range of the parent bus, and replaces the
+    /// old one in the bus maps at once, so every VM exit is routed through
+    /// either of them. Mapped memory is moved through `MemoryMapper::remap()`
+    /// and the device gets its updated resource set through `set_resources()`
+    /// before the new range becomes visible. The old range is then freed.
+    /// Doorbells move along with the resource, the VMM has to update its
+    /// `ioevents()` registrations.
+    ///
+    /// Must not be called from an access handler of the device itself.
+    pub fn relocate_resource(
+        &mut self,
+        dev: DeviceHandle,
+        res_index: usize,
         addr: GuestAddress,
-        io_type: IoType,
-    ) -> Option<(Range, &Mutex<dyn Device>)> {
-        match io_type {
-            IoType::Pio => {
-                for (range, dev) in self.pio_bus.iter().rev() {
-                    if range.0 <= addr {
-                        return Some((*range, dev));
-                    }
-                }
-                None
+    ) -> Result<()> {
+        let (name, parent_bus, old) = {
+            let descriptor = self
+                .devices
+                .values()
+                .find(|d| d.device.ptr_eq(&dev))
+                .ok_or(Error::NonExist)?;
+            let res = descriptor.resource.get(res_index).ok_or(Error::NonExist)?;
+            (descriptor.name.clone(), descriptor.parent_bus.clone(), *res)
+        };
+        if old.addr == Some(addr) {
+            return Ok(());
+        }
+        // The ranges of the children would be left outside of the container.
+        if dev.is_container(res_index) && !self.children_of(&name).is_empty() {
+            return Err(Error::ChildrenExist);
+        }
+
+        let parent = parent_bus.as_ref();
+        let new = IoResource::new(Some(addr), old.size, old.res_type);
+        let allocated = match new.res_type {
+            _ if self.sub_allocated(parent, &new) => Some(addr),
+            IoType::Pio => self.resource.allocate_io_addresses(addr, new.size),
+            IoType::PhysicalMmio | IoType::Mmio => {
+                self.resource.allocate_mmio_addresses(Some(addr), new.size)
+            }
+            // Moving into a mapped range fails when updating the bus map.
+            IoType::PciConfig | IoType::Custom(_) => Some(addr),
+        };
+        if allocated.is_none() {
+            return Err(Error::Overlap);
+        }
+
+        match self.move_resource(&name, res_index, old, new) {
+            Ok(()) => {
+                self.free_resources(parent, &[old]);
+                Ok(())
             }
-            IoType::Mmio => {
-                for (range, dev) in self.mmio_bus.iter().rev() {
-                    if range.0 <= addr {
-                        return Some((*range, dev));
+            Err(e) => {
+                self.free_resources(parent, &[new]);
+                Err(e)
+            }
+        }
+    }
+
+    /// Set the eventfd signaled when the guest moves a device resource, e.g.
+    /// by reprogramming a PCI BAR.
+    ///
+    /// The VMM then calls `relocate_moved_resources()` from a thread not
+    /// handling VM exits.
+    pub fn set_relocation_event(&mut self, event: EventFd) {
+        self.io.snapshot().set_relocation_event(event);
+    }
+
+    /// Relocate the resources the guest moved since the last call, as
+    /// reported by `Device::take_moved_resources()` after writes to the PCI
+    /// configuration space.
+    ///
+    /// Until then, the moved resources keep being decoded at their old
+    /// address. Resources of unregistered devices are skipped. All the moves
+    /// are attempted, the first error is returned.
+    ///
+    /// Must not be called from a device access handler.
+    pub fn relocate_moved_resources(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for (dev, res_index, addr) in self.io.snapshot().take_moved_resources() {
+            match self.relocate_resource(dev, res_index, addr) {
+                Ok(()) | Err(Error::NonExist) => (),
+                Err(e) => {
+                    if result.is_ok() {
+                        result = Err(e);
                     }
                 }
-                None
             }
-            IoType::PhysicalMmio => None,
         }
+        result
     }
 
-    /// Return the Device mapped the address.
-    fn get_device(&self, addr: GuestAddress, io_type: IoType) -> Option<&Mutex<dyn Device>> {
-        if let Some((Range(start, len), dev)) = self.first_before(addr, io_type) {
-            if (addr.0 - start.0) < len {
-                return Some(dev);
-            }
+    fn move_resource(
+        &mut self,
+        name: &str,
+        res_index: usize,
+        old: IoResource,
+        new: IoResource,
+    ) -> Result<()> {
+        let (old_addr, new_addr) = (old.addr.unwrap(), new.addr.unwrap());
+        let descriptor = self.devices.get_mut(name).ok_or(Error::NonExist)?;
+        let mut buses = (*self.io.snapshot()).clone();
+        if let Some(bus) = buses.bus_mut(old.res_type) {
+            let parent = descriptor
+                .parent_bus
+                .as_ref()
+                .map(|bus| DeviceHandle::Exclusive(bus.clone()));
+            let entry = bus
+                .remove(&Range(old_addr, old.size), &descriptor.device)
+                .ok_or(Error::NonExist)?;
+            bus.insert(Range(new_addr, new.size), entry, parent.as_ref())?;
         }
-        None
+
+        let backing = descriptor
+            .memory
+            .iter()
+            .find(|(idx, _)| *idx == res_index)
+            .map(|(_, backing)| backing);
+        if let (Some(mapper), Some(backing)) = (self.memory_mapper.as_ref(), backing) {
+            mapper
+                .remap(old_addr, new_addr, new.size, backing)
+                .map_err(|cause| Error::MapMemory {
+                    name: name.to_string(),
+                    cause,
+                })?;
+        }
+
+        descriptor.resource[res_index] = new;
+        descriptor
+            .device
+            .set_resources(&descriptor.resource, descriptor.irq);
+        self.io.publish(buses);
+        Ok(())
     }
 
-    /// A helper function handling PIO/MMIO read commands during VM exit.
+    /// Route the accesses to `range` of the address space `io_type` to the
+    /// resource `res_index` of a registered device, starting `offset` bytes
+    /// into it.
     ///
-    /// Figure out the device according to `addr` and hand over the handling to device
-    /// specific read function.
-    /// Return error if failed to get the device.
-    pub fn read(&self, addr: GuestAddress, data: &mut [u8], io_type: IoType) -> Result<()> {
-        if let Some(dev) = self.get_device(addr, io_type) {
-            dev.lock()
-                .expect("Failed to acquire device lock")
-                .read(addr, data, io_type);
-            Ok(())
-        } else {
-            Err(Error::NonExist)
+    /// Aliases take precedence over the ranges of all devices, e.g. for VGA
+    /// legacy ranges mirroring part of a framebuffer. No address gets
+    /// allocated for them and they must not overlap each other. They are
+    /// removed along with the device, and are not part of the saved state.
+    pub fn add_alias(
+        &mut self,
+        range: Range,
+        io_type: IoType,
+        dev: DeviceHandle,
+        res_index: usize,
+        offset: GuestUsize,
+    ) -> Result<()> {
+        let res = self
+            .devices
+            .values()
+            .find(|d| d.device.ptr_eq(&dev))
+            .and_then(|d| d.resource.get(res_index))
+            .ok_or(Error::NonExist)?;
+        if offset.checked_add(range.1).is_none_or(|end| end > res.size) {
+            return Err(Error::Oversize);
         }
+        let mut buses = (*self.io.snapshot()).clone();
+        let bus = self
+            .trapping_bus(&mut buses, io_type)
+            .ok_or(Error::InvalidAddressSpace)?;
+        let entry = BusEntry {
+            index: res_index,
+            offset,
+            device: dev,
+            doorbells: Vec::new(),
+            container: false,
+        };
+        bus.insert_alias(range, entry)?;
+        self.io.publish(buses);
+        Ok(())
     }
 
-    /// A helper function handling PIO/MMIO write commands during VM exit.
+    /// Remove the alias starting at `addr` of the address space `io_type`.
+    pub fn remove_alias(&mut self, addr: GuestAddress, io_type: IoType) -> Result<()> {
+        let mut buses = (*self.io.snapshot()).clone();
+        buses
+            .bus_mut(io_type)
+            .and_then(|bus| bus.remove_alias(addr))
+            .ok_or(Error::NonExist)?;
+        self.io.publish(buses);
+        Ok(())
+    }
+
+    /// Return the descriptor of the device registered as `name`.
+    pub fn device(&self, name: &str) -> Option<&DeviceDescriptor> {
+        self.devices.get(name)
+    }
+
+    /// Return the descriptor of the parent bus of the device `name`, if the
+    /// bus is registered.
+    pub fn parent_of(&self, name: &str) -> Option<&DeviceDescriptor> {
+        let child = self.devices.get(name)?;
+        self.devices.values().find(|bus| child.is_child_of(bus))
+    }
+
+    /// Return the descriptors of the devices sitting on the bus `name`, sorted by name.
+    pub fn children_of(&self, name: &str) -> Vec<&DeviceDescriptor> {
+        let mut children: Vec<&DeviceDescriptor> = match self.devices.get(name) {
+            Some(bus) => self
+                .devices
+                .values()
+                .filter(|d| d.is_child_of(bus))
+                .collect(),
+            None => Vec::new(),
+        };
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+        children
+    }
+
+    /// Return the descriptors of all devices, in depth-first order.
     ///
-    /// Figure out the device according to `addr` and hand over the handling to device
-    /// specific write function.
-    /// Return error if failed to get the device.
-    pub fn write(&self, addr: GuestAddress, data: &[u8], io_type: IoType) -> Result<()> {
-        if let Some(dev) = self.get_device(addr, io_type) {
-            dev.lock()
-                .expect("Failed to acquire device lock")
-                .write(addr, data, io_type);
-            Ok(())
-        } else {
-            Err(Error::NonExist)
+    /// Devices without a registered parent bus are the roots of the topology.
+    /// Siblings are visited by name order.
+    pub fn walk(&self) -> Vec<&DeviceDescriptor> {
+        let mut roots: Vec<&DeviceDescriptor> = self
+            .devices
+            .values()
+            .filter(|d| self.parent_of(&d.name).is_none())
+            .collect();
+        roots.sort_by(|a, b| b.name.cmp(&a.name));
+
+        // Depth-first walk with an explicit stack, pushing siblings in
+        // reverse order so that they pop out sorted.
+        let mut order = Vec::with_capacity(self.devices.len());
+        let mut stack = roots;
+        while let Some(descriptor) = stack.pop() {
+            order.push(descriptor);
+            stack.extend(self.children_of(&descriptor.name).into_iter().rev());
         }
+        order
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::device::*;
-    use crate::device_manager::*;
-    use std::string::String;
+    /// Return the topology path of the device `name`, e.g. `/pci0/00:03.0`.
+    pub fn path_of(&self, name: &str) -> Option<String> {
+        let mut descriptor = self.devices.get(name)?;
+        let mut path = format!("/{}", descriptor.name);
+        while let Some(bus) = self.parent_of(&descriptor.name) {
+            path = format!("/{}{}", bus.name, path);
+            descriptor = bus;
+        }
+        Some(path)
+    }
 
-    #[test]
-    fn test_dev_init() -> Result<()> {
-        pub struct BusDevice {
-            pub config_address: u32,
-            pub name: String,
+    /// Return the descriptor of the device at the topology path `path`.
+    pub fn device_by_path(&self, path: &str) -> Option<&DeviceDescriptor> {
+        let name = path.rsplit('/').next()?;
+        match self.path_of(name) {
+            Some(ref p) if p == path => self.devices.get(name),
+            _ => None,
         }
-        impl Device for BusDevice {
-            /// Get the device name.
-            fn name(&self) -> String {
-                self.name.clone()
-            }
-            /// Read operation.
-            fn read(&mut self, _addr: GuestAddress, data: &mut [u8], _io_type: IoType) {
-                if data.len() > 4 {
-                    for d in data {
-                        *d = 0xff;
-                    }
-                    return;
-                }
-                for i in 0..data.len() {
-                    data[i] = (self.config_address >> (i * 8) & 0xff) as u8;
+    }
+
+    /// Return the eventfd registrations of the device doorbells.
+    ///
+    /// The VMM registers them, e.g. with `KVM_IOEVENTFD`, to skip the VM exits
+    /// of the matching writes. It needs to update its registrations after
+    /// devices got hot-plugged or hot-unplugged. Doorbells outside of a PIO or
+    /// MMIO resource are ignored.
+    pub fn ioevents(&self) -> Vec<IoEvent> {
+        let mut ioevents = Vec::new();
+        for descriptor in self.devices.values() {
+            for doorbell in descriptor.doorbells.iter() {
+                let res = match descriptor.resource.get(doorbell.res_index) {
+                    Some(res) if doorbell.offset < res.size => res,
+                    _ => continue,
+                };
+                if let (IoType::Pio, Some(addr)) | (IoType::Mmio, Some(addr)) =
+                    (res.res_type, res.addr)
+                {
+                    ioevents.push(IoEvent {
+                        addr: GuestAddress(addr.0 + doorbell.offset),
+                        io_type: res.res_type,
+                        len: doorbell.len,
+                        datamatch: doorbell.datamatch,
+                        event: doorbell.event.clone(),
+                    });
                 }
             }
-            /// Write operation.
-            fn write(&mut self, _addr: GuestAddress, data: &[u8], _io_type: IoType) {
-                self.config_address = data[0] as u32 & 0xff;
-            }
-            /// Set the allocated resource to device.
-            ///
-            /// This will be called by DeviceManager::register_device() to set
-            /// the allocated resource from the vm_allocator back to device.
-            fn set_resources(&mut self, _res: &[IoResource], _irq: Option<IrqResource>) {}
         }
-        impl BusDevice {
-            pub fn new(name: String) -> Self {
-                BusDevice {
-                    name,
-                    config_address: 0x1000,
-                }
-            }
-            pub fn get_resource(&self) -> Vec<IoResource> {
-                let mut req_vec = Vec::new();
-                let res = IoResource::new(Some(GuestAddress(0xcf8)), 8 as GuestUsize, IoType::Pio);
+        ioevents
+    }
 
-                req_vec.push(res);
-                req_vec
-            }
+    /// Return the guest kernel command line parameters declaring the
+    /// registered virtio-mmio devices, by increasing address.
+    ///
+    /// Each parameter is `virtio_mmio.device=<size>@<addr>:<irq>`, built from
+    /// the first MMIO resource and the IRQ allocated to the device. Devices
+    /// lacking any of them are skipped.
+    pub fn virtio_mmio_cmdline(&self) -> Vec<String> {
+        let mut devices: Vec<(GuestAddress, GuestUsize, u32)> = self
+            .devices
+            .values()
+            .filter(|descriptor| descriptor.device.is_virtio_mmio())
+            .filter_map(|descriptor| {
+                let res = descriptor
+                    .resource
+                    .iter()
+                    .find(|res| res.res_type == IoType::Mmio)?;
+                let irq = descriptor.irq?.irqs().start;
+                Some((res.addr?, res.size, irq))
+            })
+            .collect();
+        devices.sort();
+        devices
+            .into_iter()
+            .map(|(addr, size, irq)| {
+                format!("virtio_mmio.device={:#x}@{:#x}:{}", size, addr.0, irq)
+            })
+            .collect()
+    }
+
+    /// Write the device tree nodes of the registered devices as children of
+    /// the current node of `fdt`, usually the root node.
+    ///
+    /// Each device gets a `<name>@<addr>` node nested in the node of its
+    /// parent bus, with a `reg` property listing its MMIO resources and an
+    /// `interrupts` property built by `irq_cells` from its legacy IRQ.
+    /// Addresses and sizes take two cells and bus nodes get an empty `ranges`
+    /// property. `Device::fdt_properties()` completes each node.
+    pub fn write_fdt(&self, fdt: &mut FdtWriter, irq_cells: &FdtIrqCells) -> fdt::Result<()> {
+        let mut roots: Vec<&DeviceDescriptor> = self
+            .devices
+            .values()
+            .filter(|d| self.parent_of(&d.name).is_none())
+            .collect();
+        roots.sort_by(|a, b| a.name.cmp(&b.name));
+        for descriptor in roots {
+            self.write_fdt_node(fdt, descriptor, irq_cells)?;
+        }
+        Ok(())
+    }
+
+    fn write_fdt_node(
+        &self,
+        fdt: &mut FdtWriter,
+        descriptor: &DeviceDescriptor,
+        irq_cells: &FdtIrqCells,
+    ) -> fdt::Result<()> {
+        let mut reg = Vec::new();
+        for res in descriptor.resource.iter() {
+            if let (IoType::Mmio, Some(addr)) | (IoType::PhysicalMmio, Some(addr)) =
+                (res.res_type, res.addr)
+            {
+                reg.extend_from_slice(&[
+                    (addr.0 >> 32) as u32,
+                    addr.0 as u32,
+                    (res.size >> 32) as u32,
+                    res.size as u32,
+                ]);
+            }
+        }
+        let mut name = fdt::node_name(&descriptor.name);
+        if !reg.is_empty() {
+            name = format!("{}@{:x}", name, u64::from(reg[0]) << 32 | u64::from(reg[1]));
+        }
+        fdt.begin_node(&name)?;
+        if !reg.is_empty() {
+            fdt.property(&FdtProperty::cells("reg", &reg))?;
+        }
+        match descriptor.irq {
+            Some(IrqResource::Legacy(Some(irq))) => {
+                fdt.property(&FdtProperty::cells("interrupts", &irq_cells(irq, false)))?
+            }
+            Some(IrqResource::SharedLegacy(Some(irq))) => {
+                fdt.property(&FdtProperty::cells("interrupts", &irq_cells(irq, true)))?
+            }
+            _ => (),
+        }
+        let children = self.children_of(&descriptor.name);
+        if !children.is_empty() {
+            fdt.property(&FdtProperty::u32("#address-cells", 2))?;
+            fdt.property(&FdtProperty::u32("#size-cells", 2))?;
+            fdt.property(&FdtProperty::empty("ranges"))?;
+        }
+        for prop in descriptor.device.fdt_properties() {
+            fdt.property(&prop)?;
+        }
+        for child in children {
+            self.write_fdt_node(fdt, child, irq_cells)?;
+        }
+        fdt.end_node()
+    }
+
+    /// Return the `Scope(\_SB_)` AML object holding the device objects of the
+    /// registered devices with an ACPI description, in `walk()` order.
+    ///
+    /// Each device object gets the `_HID` and `_UID` of the device and a `_CRS`
+    /// built from its allocated PIO and MMIO resources and legacy IRQ. The VMM
+    /// appends it to the DSDT body.
+    pub fn dsdt_aml(&self) -> acpi::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        for descriptor in self.walk() {
+            if let Some(aml) = descriptor
+                .device
+                .acpi_aml(&descriptor.resource, descriptor.irq)
+            {
+                body.extend(aml?);
+            }
+        }
+        Ok(acpi::system_bus_scope(&body))
+    }
+
+    /// Return the MADT IO APIC structures of the registered interrupt
+    /// controllers.
+    pub fn madt_entries(&self) -> Vec<u8> {
+        self.acpi_table_entries(|entry| matches!(entry, AcpiTableEntry::IoApic { .. }))
+    }
+
+    /// Return the MCFG configuration space base address allocation structures
+    /// of the registered PCI ECAM windows.
+    pub fn mcfg_entries(&self) -> Vec<u8> {
+        self.acpi_table_entries(|entry| matches!(entry, AcpiTableEntry::PciEcam { .. }))
+    }
+
+    fn acpi_table_entries<F: Fn(&AcpiTableEntry) -> bool>(&self, filter: F) -> Vec<u8> {
+        let mut entries = Vec::new();
+        for descriptor in self.walk() {
+            let entry = match descriptor.device.acpi_table_entry() {
+                Some(entry) if filter(&entry) => entry,
+                _ => continue,
+            };
+            let addr = descriptor
+                .resource
+                .iter()
+                .find(|res| res.res_type == IoType::Mmio)
+                .and_then(|res| res.addr);
+            if let Some(addr) = addr {
+                entries.extend(acpi::table_entry(entry, addr.0));
+            }
+        }
+        entries
+    }
+
+    /// Save the state of all the registered devices and of the `SystemAllocator`.
+    ///
+    /// Devices are saved parents first, with their allocated resources and IRQ
+    /// and the device specific state they expose through `snapshot()`.
+    pub fn save_state(&self) -> DeviceManagerState {
+        let devices = self
+            .walk()
+            .into_iter()
+            .map(|descriptor| DeviceState {
+                name: descriptor.name.clone(),
+                parent_bus: self.parent_of(&descriptor.name).map(|bus| bus.name.clone()),
+                resource: descriptor.resource.clone(),
+                irq: descriptor.irq,
+                state: descriptor.device.save_state(),
+            })
+            .collect();
+
+        DeviceManagerState {
+            allocator: self.resource.save_state(),
+            devices,
+        }
+    }
+
+    /// Restore a state returned by `save_state()` into this empty `DeviceManager`.
+    ///
+    /// `devices` are the new device objects, matched with the saved ones by
+    /// name. Nothing gets allocated: the `SystemAllocator` state is restored
+    /// as saved, every device gets its interrupt sources and its saved
+    /// resources and IRQ through `set_resources()` followed by its device specific state, and the
+    /// devices are then mapped at their saved addresses. Custom address
+    /// spaces must have been added in the same order as when saving.
+    ///
+    /// The devices and the saved ranges are checked before any device gets
+    /// its resources. If restoring fails afterwards, the memory mapped so far
+    /// is unmapped and the `SystemAllocator` gets its previous state back.
+    pub fn restore_state(
+        &mut self,
+        state: &DeviceManagerState,
+        devices: Vec<DeviceHandle>,
+    ) -> Result<()> {
+        if !self.devices.is_empty() {
+            return Err(Error::Exist);
+        }
+
+        let mut handles: HashMap<String, DeviceHandle> =
+            devices.into_iter().map(|dev| (dev.name(), dev)).collect();
+        let mut descriptors: Vec<DeviceDescriptor> = Vec::new();
+        for saved in state.devices.iter() {
+            let dev = handles.remove(&saved.name).ok_or(Error::InvalidSnapshot)?;
+            // Parents are saved before their children.
+            let parent_bus = match saved.parent_bus {
+                Some(ref name) => match descriptors.iter().find(|d| d.name == *name) {
+                    Some(DeviceDescriptor {
+                        device: DeviceHandle::Exclusive(bus),
+                        ..
+                    }) => Some(bus.clone()),
+                    _ => return Err(Error::InvalidSnapshot),
+                },
+                None => None,
+            };
+            descriptors.push(DeviceDescriptor::new(
+                saved.name.clone(),
+                dev,
+                parent_bus,
+                saved.resource.clone(),
+                saved.irq,
+            ));
+        }
+        if !handles.is_empty() {
+            return Err(Error::InvalidSnapshot);
+        }
+
+        // Keep the fallbacks, no range is mapped without devices.
+        let mut buses = (*self.io.snapshot()).clone();
+        let mut check = buses.clone();
+        for descriptor in descriptors.iter() {
+            if descriptor.resource.iter().any(|res| res.addr.is_none()) {
+                return Err(Error::InvalidSnapshot);
+            }
+            Self::map_resource(
+                &mut check,
+                &descriptor.device,
+                descriptor.parent_bus.as_ref(),
+                &descriptor.resource,
+                &[],
+            )?;
+        }
+
+        let previous = self.resource.save_state();
+        self.resource
+            .restore_state(&state.allocator)
+            .ok_or(Error::InvalidSnapshot)?;
+        if let Err(e) = self.restore_devices(state, &mut descriptors, &mut buses) {
+            for descriptor in descriptors.iter() {
+                let _ =
+                    self.unmap_memory(&descriptor.name, &descriptor.resource, &descriptor.memory);
+            }
+            // No device shares a line in an empty `DeviceManager`.
+            self.irq_lines.clear();
+            self.resource.restore_state(&previous);
+            return Err(e);
+        }
+
+        for descriptor in descriptors {
+            self.devices.insert(descriptor.name.clone(), descriptor);
+        }
+        self.io.publish(buses);
+        Ok(())
+    }
+
+    fn restore_devices(
+        &mut self,
+        state: &DeviceManagerState,
+        descriptors: &mut [DeviceDescriptor],
+        buses: &mut IoBuses,
+    ) -> Result<()> {
+        for (descriptor, saved) in descriptors.iter_mut().zip(state.devices.iter()) {
+            self.set_interrupt_group(&descriptor.device, descriptor.irq);
+            descriptor
+                .device
+                .set_resources(&descriptor.resource, descriptor.irq);
+            if let Some((version, ref data)) = saved.state {
+                descriptor
+                    .device
+                    .restore_state(version, data)
+                    .map_err(|cause| Error::RestoreDevice {
+                        name: descriptor.name.clone(),
+                        cause,
+                    })?;
+            }
+            descriptor.doorbells = descriptor.device.doorbells();
+            descriptor.memory = self.map_memory(&descriptor.device, &descriptor.resource)?;
+            Self::map_resource(
+                buses,
+                &descriptor.device,
+                descriptor.parent_bus.as_ref(),
+                &descriptor.resource,
+                &descriptor.doorbells,
+            )?;
         }
+        Ok(())
+    }
+
+    /// A helper function handling PIO/MMIO read commands during VM exit.
+    ///
+    /// Figure out the device according to `addr` and hand over the handling to device
+    /// specific read function, with `addr` translated to a resource index and offset.
+    /// Accesses no device claims are handled by the `Fallback` of the address
+    /// space, and the ones crossing range boundaries by its `SpanPolicy`.
+    /// Return error if failed to get the device or if the device failed
+    /// to handle the access.
+    pub fn read(&self, addr: GuestAddress, data: &mut [u8], io_type: IoType) -> Result<()> {
+        self.io.read(addr, data, io_type)
+    }
+
+    /// A helper function handling PIO/MMIO write commands during VM exit.
+    ///
+    /// Figure out the device according to `addr` and hand over the handling to device
+    /// specific write function, with `addr` translated to a resource index and offset.
+    /// Accesses no device claims are handled by the `Fallback` of the address
+    /// space, and the ones crossing range boundaries by its `SpanPolicy`.
+    /// Return error if failed to get the device or if the device failed
+    /// to handle the access.
+    pub fn write(&self, addr: GuestAddress, data: &[u8], io_type: IoType) -> Result<()> {
+        self.io.write(addr, data, io_type)
+    }
+}
 
-        let mut sys_res = SystemAllocator::new(
+#[cfg(test)]
+mod tests {
+    extern crate vmm_sys_util;
+
+    use self::vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+    use crate::bus::{MAX_UNCLAIMED_ADDRESSES, MAX_UNCLAIMED_LOG};
+    use crate::device;
+    use crate::device::{Device, DeviceHandle, IoResource, IoType, IrqResource, SharedDevice};
+    use crate::device_manager::*;
+    use crate::interrupt::{InterruptEvent, InterruptRecorder, InterruptSourceGroup};
+    use crate::ioevent::Doorbell;
+    use crate::memory::{MemoryBacking, MemoryMapper};
+    use crate::snapshot::Snapshot;
+    use std::string::String;
+
+    pub struct BusDevice {
+        pub config_address: u32,
+        pub name: String,
+        pub last_access: Option<(usize, GuestUsize)>,
+        pub children: Vec<String>,
+        pub interrupt: Option<Arc<dyn InterruptSourceGroup>>,
+        pub containers: Vec<usize>,
+    }
+
+    impl Device for BusDevice {
+        /// Get the device name.
+        fn name(&self) -> String {
+            self.name.clone()
+        }
+        /// Read operation.
+        fn read(
+            &mut self,
+            res_index: usize,
+            offset: GuestUsize,
+            data: &mut [u8],
+            _io_type: IoType,
+        ) -> device::Result<()> {
+            self.last_access = Some((res_index, offset));
+            if data.len() > 4 {
+                for d in data {
+                    *d = 0xff;
+                }
+                return Ok(());
+            }
+            for (i, d) in data.iter_mut().enumerate() {
+                *d = (self.config_address >> (i * 8) & 0xff) as u8;
+            }
+            Ok(())
+        }
+        /// Write operation.
+        fn write(
+            &mut self,
+            res_index: usize,
+            offset: GuestUsize,
+            data: &[u8],
+            _io_type: IoType,
+        ) -> device::Result<()> {
+            self.last_access = Some((res_index, offset));
+            if data.is_empty() {
+                return Err(device::Error::InvalidAccessWidth(0));
+            }
+            self.config_address = data[0] as u32 & 0xff;
+            Ok(())
+        }
+        /// Set the allocated resource to device.
+        ///
+        /// This will be called by DeviceManager::register_device() to set
+        /// the allocated resource from the vm_allocator back to device.
+        fn set_resources(&mut self, _res: &[IoResource], _irq: Option<IrqResource>) {}
+        fn set_interrupt_group(&mut self, group: Arc<dyn InterruptSourceGroup>) {
+            self.interrupt = Some(group);
+        }
+        fn memory_backing(&self, res_index: usize) -> Option<MemoryBacking> {
+            Some(MemoryBacking::Host {
+                addr: 0x7f00_0000 + res_index * 0x10000,
+            })
+        }
+        fn is_container(&self, res_index: usize) -> bool {
+            self.containers.contains(&res_index)
+        }
+        fn child_added(&mut self, name: &str, _res: &[IoResource]) {
+            self.children.push(name.to_string());
+        }
+        fn child_removed(&mut self, name: &str) {
+            self.children.retain(|child| child != name);
+        }
+        fn snapshot(&mut self) -> Option<&mut dyn Snapshot> {
+            Some(self)
+        }
+    }
+
+    impl Snapshot for BusDevice {
+        fn version(&self) -> u32 {
+            1
+        }
+        fn save(&self) -> Vec<u8> {
+            self.config_address.to_le_bytes().to_vec()
+        }
+        fn restore(&mut self, version: u32, data: &[u8]) -> device::Result<()> {
+            if version != 1 || data.len() != 4 {
+                return Err(device::Error::Internal("invalid state".to_string()));
+            }
+            let mut config_address = [0u8; 4];
+            config_address.copy_from_slice(data);
+            self.config_address = u32::from_le_bytes(config_address);
+            Ok(())
+        }
+    }
+
+    impl BusDevice {
+        pub fn new(name: String) -> Self {
+            BusDevice {
+                name,
+                config_address: 0x1000,
+                last_access: None,
+                children: Vec::new(),
+                interrupt: None,
+                containers: Vec::new(),
+            }
+        }
+        pub fn get_resource(&self) -> Vec<IoResource> {
+            let mut req_vec = Vec::new();
+            let res = IoResource::new(Some(GuestAddress(0xcf8)), 8 as GuestUsize, IoType::Pio);
+
+            req_vec.push(res);
+            req_vec
+        }
+    }
+
+    fn system_allocator() -> SystemAllocator {
+        SystemAllocator::new(
             Some(GuestAddress(0x100)),
             Some(0x10000),
             GuestAddress(0x10000000),
             0x10000000,
             5,
         )
-        .unwrap();
+        .unwrap()
+    }
+
+    #[test]
+    fn test_dev_init() -> Result<()> {
+        let mut sys_res = system_allocator();
         let mut dev_mgr = DeviceManager::new(&mut sys_res);
         let dummy_bus = BusDevice::new("dummy-bus".to_string());
         let mut res_req = dummy_bus.get_resource();
@@ -393,8 +1546,1328 @@ mod tests {
             Arc::new(Mutex::new(dummy_bus)),
             None,
             &mut res_req,
-            Some(IrqResource(None)),
+            Some(IrqResource::Legacy(None)),
         )
     }
 
+    #[test]
+    fn test_dev_access_offset() -> Result<()> {
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let dummy = Arc::new(Mutex::new(BusDevice::new("dummy".to_string())));
+        let mut res_req = vec![
+            IoResource::new(Some(GuestAddress(0xcf8)), 8, IoType::Pio),
+            IoResource::new(None, 0x1000, IoType::Mmio),
+        ];
+
+        dev_mgr.register_device(dummy.clone(), None, &mut res_req, None)?;
+        let mmio_base = res_req[1].addr.unwrap();
+
+        let mut data = [0u8; 4];
+        dev_mgr.read(GuestAddress(0xcfc), &mut data, IoType::Pio)?;
+        assert_eq!(dummy.lock().unwrap().last_access, Some((0, 4)));
+
+        dev_mgr.write(GuestAddress(mmio_base.0 + 0x10), &data, IoType::Mmio)?;
+        assert_eq!(dummy.lock().unwrap().last_access, Some((1, 0x10)));
+
+        // Accesses outside of any registered range are not routed.
+        assert!(dev_mgr
+            .read(GuestAddress(0xd00), &mut data, IoType::Pio)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dev_access_error() {
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let dummy = BusDevice::new("dummy".to_string());
+        let mut res_req = dummy.get_resource();
+
+        dev_mgr
+            .register_device(Arc::new(Mutex::new(dummy)), None, &mut res_req, None)
+            .unwrap();
+
+        match dev_mgr.write(GuestAddress(0xcf9), &[], IoType::Pio) {
+            Err(Error::DeviceAccess {
+                name,
+                addr,
+                io_type: IoType::Pio,
+                cause: DeviceError::InvalidAccessWidth(0),
+            }) => {
+                assert_eq!(name, "dummy");
+                assert_eq!(addr, GuestAddress(0xcf9));
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_dev_io_dispatcher() -> Result<()> {
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let dispatcher = dev_mgr.io_dispatcher();
+        let dummy: Arc<Mutex<dyn Device>> =
+            Arc::new(Mutex::new(BusDevice::new("dummy".to_string())));
+        let mut res_req = vec![IoResource::new(Some(GuestAddress(0xcf8)), 8, IoType::Pio)];
+
+        dev_mgr.register_device(dummy.clone(), None, &mut res_req, None)?;
+
+        let vcpus: Vec<_> = (0..4)
+            .map(|_| {
+                let io = dispatcher.clone();
+                std::thread::spawn(move || {
+                    let mut data = [0u8; 2];
+                    io.read(GuestAddress(0xcf8), &mut data, IoType::Pio)
+                        .unwrap();
+                    data
+                })
+            })
+            .collect();
+        for vcpu in vcpus {
+            assert_eq!(vcpu.join().unwrap(), [0x00, 0x10]);
+        }
+
+        dev_mgr.unregister_device(dummy)?;
+        let mut data = [0u8; 2];
+        assert!(dispatcher
+            .read(GuestAddress(0xcf8), &mut data, IoType::Pio)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_device() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct Counter {
+            count: AtomicUsize,
+        }
+
+        impl SharedDevice for Counter {
+            fn name(&self) -> String {
+                "counter".to_string()
+            }
+            fn read(
+                &self,
+                _res_index: usize,
+                _offset: GuestUsize,
+                data: &mut [u8],
+                _io_type: IoType,
+            ) -> device::Result<()> {
+                data[0] = self.count.load(Ordering::SeqCst) as u8;
+                Ok(())
+            }
+            fn write(
+                &self,
+                _res_index: usize,
+                _offset: GuestUsize,
+                _data: &[u8],
+                _io_type: IoType,
+            ) -> device::Result<()> {
+                self.count.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+            fn set_resources(&self, _res: &[IoResource], _irq: Option<IrqResource>) {}
+        }
+
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let counter: Arc<dyn SharedDevice> = Arc::new(Counter {
+            count: AtomicUsize::new(0),
+        });
+        let mut res_req = vec![IoResource::new(None, 0x1000, IoType::Mmio)];
+        dev_mgr.register_shared_device(counter.clone(), None, &mut res_req, None)?;
+
+        // Mutex wrapped and shared devices live side by side.
+        let dummy = BusDevice::new("dummy".to_string());
+        let mut dummy_req = dummy.get_resource();
+        dev_mgr.register_device(Arc::new(Mutex::new(dummy)), None, &mut dummy_req, None)?;
+
+        let addr = res_req[0].addr.unwrap();
+        let dispatcher = dev_mgr.io_dispatcher();
+        let vcpus: Vec<_> = (0..4)
+            .map(|_| {
+                let io = dispatcher.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        io.write(addr, &[0], IoType::Mmio).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for vcpu in vcpus {
+            vcpu.join().unwrap();
+        }
+
+        let mut data = [0u8; 1];
+        dev_mgr.read(addr, &mut data, IoType::Mmio)?;
+        assert_eq!(data[0], 40);
+        dev_mgr.read(GuestAddress(0xcf8), &mut data, IoType::Pio)?;
+        assert_eq!(data[0], 0);
+
+        dev_mgr.unregister_shared_device(counter)?;
+        assert!(dev_mgr.read(addr, &mut data, IoType::Mmio).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hotplug() -> Result<()> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::thread;
+        use std::time::Duration;
+
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let bus = Arc::new(Mutex::new(BusDevice::new("bus".to_string())));
+        let mut bus_req = bus.lock().unwrap().get_resource();
+        dev_mgr.register_device(bus.clone(), None, &mut bus_req, None)?;
+
+        let child = Arc::new(Mutex::new(BusDevice::new("child".to_string())));
+        let mut res_req = vec![IoResource::new(None, 0x1000, IoType::Mmio)];
+        dev_mgr.hotplug_device(
+            DeviceHandle::Exclusive(child.clone()),
+            Some(bus.clone()),
+            &mut res_req,
+            None,
+        )?;
+        let addr = res_req[0].addr.unwrap();
+        assert_eq!(bus.lock().unwrap().children, vec!["child".to_string()]);
+        let mut data = [0u8; 1];
+        dev_mgr.read(addr, &mut data, IoType::Mmio)?;
+
+        // A VM exit routed through bus maps replaced twice since then still
+        // holds off the unplug.
+        let in_flight = dev_mgr.io.snapshot();
+        let done = Arc::new(AtomicBool::new(false));
+        let exited = done.clone();
+        let vcpu = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            exited.store(true, Ordering::SeqCst);
+            drop(in_flight);
+        });
+        dev_mgr.set_fallback(IoType::Mmio, Fallback::AllOnes)?;
+        dev_mgr.set_fallback(IoType::Mmio, Fallback::Fail)?;
+
+        // Unplugging does not depend on the current device name.
+        child.lock().unwrap().name = "renamed".to_string();
+        dev_mgr.hot_unplug_device(DeviceHandle::Exclusive(child.clone()))?;
+        assert!(done.load(Ordering::SeqCst));
+        vcpu.join().unwrap();
+        assert!(bus.lock().unwrap().children.is_empty());
+        assert!(dev_mgr.read(addr, &mut data, IoType::Mmio).is_err());
+        assert!(dev_mgr
+            .hot_unplug_device(DeviceHandle::Exclusive(child))
+            .is_err());
+
+        // The freed range is available again.
+        let other = Arc::new(Mutex::new(BusDevice::new("other".to_string())));
+        let mut other_req = vec![IoResource::new(None, 0x1000, IoType::Mmio)];
+        dev_mgr.hotplug_device(
+            DeviceHandle::Exclusive(other),
+            Some(bus.clone()),
+            &mut other_req,
+            None,
+        )?;
+        assert_eq!(other_req[0].addr, Some(addr));
+        assert_eq!(bus.lock().unwrap().children, vec!["other".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_topology() -> Result<()> {
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let pci0 = Arc::new(Mutex::new(BusDevice::new("pci0".to_string())));
+        let mut pci0_req = pci0.lock().unwrap().get_resource();
+        dev_mgr.register_device(pci0.clone(), None, &mut pci0_req, None)?;
+
+        let bridge = Arc::new(Mutex::new(BusDevice::new("00:02.0".to_string())));
+        dev_mgr.register_device(bridge.clone(), Some(pci0.clone()), &mut Vec::new(), None)?;
+        for name in &["00:03.0", "00:01.0"] {
+            let dev = Arc::new(Mutex::new(BusDevice::new(name.to_string())));
+            dev_mgr.register_device(dev, Some(pci0.clone()), &mut Vec::new(), None)?;
+        }
+        let nic = Arc::new(Mutex::new(BusDevice::new("01:00.0".to_string())));
+        dev_mgr.register_device(nic.clone(), Some(bridge.clone()), &mut Vec::new(), None)?;
+        let rtc = Arc::new(Mutex::new(BusDevice::new("rtc".to_string())));
+        dev_mgr.register_device(rtc, None, &mut Vec::new(), None)?;
+
+        let names = |v: Vec<&DeviceDescriptor>| -> Vec<String> {
+            v.into_iter().map(|d| d.name.clone()).collect()
+        };
+        assert_eq!(
+            names(dev_mgr.children_of("pci0")),
+            vec!["00:01.0", "00:02.0", "00:03.0"]
+        );
+        assert!(dev_mgr.children_of("rtc").is_empty());
+        assert_eq!(dev_mgr.parent_of("01:00.0").unwrap().name, "00:02.0");
+        assert!(dev_mgr.parent_of("pci0").is_none());
+        assert_eq!(
+            names(dev_mgr.walk()),
+            vec!["pci0", "00:01.0", "00:02.0", "01:00.0", "00:03.0", "rtc"]
+        );
+        assert_eq!(
+            dev_mgr.path_of("01:00.0"),
+            Some("/pci0/00:02.0/01:00.0".to_string())
+        );
+        assert_eq!(
+            dev_mgr.device_by_path("/pci0/00:03.0").unwrap().name,
+            "00:03.0"
+        );
+        assert!(dev_mgr.device_by_path("/00:03.0").is_none());
+
+        // A bus can not go away before its children.
+        match dev_mgr.unregister_device(bridge.clone()) {
+            Err(Error::ChildrenExist) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        dev_mgr.unregister_device(nic)?;
+        dev_mgr.unregister_device(bridge)?;
+        assert_eq!(
+            names(dev_mgr.children_of("pci0")),
+            vec!["00:01.0", "00:03.0"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_restore() -> Result<()> {
+        let mut sys_res = system_allocator();
+        let saved = {
+            let mut dev_mgr = DeviceManager::new(&mut sys_res);
+            let bus = Arc::new(Mutex::new(BusDevice::new("bus".to_string())));
+            let mut bus_req = bus.lock().unwrap().get_resource();
+            dev_mgr.register_device(bus.clone(), None, &mut bus_req, None)?;
+            let dev = Arc::new(Mutex::new(BusDevice::new("dev".to_string())));
+            let mut dev_req = vec![
+                IoResource::new(None, 0x1000, IoType::Mmio),
+                IoResource::new(None, 0x2000, IoType::Mmio),
+            ];
+            dev_mgr.register_device(
+                dev.clone(),
+                Some(bus),
+                &mut dev_req,
+                Some(IrqResource::Legacy(None)),
+            )?;
+            dev_mgr.write(dev_req[1].addr.unwrap(), &[0x42], IoType::Mmio)?;
+            dev_mgr.save_state().serialize()
+        };
+
+        let state = DeviceManagerState::deserialize(&saved)?;
+        let mut restored_res = system_allocator();
+        {
+            let mut dev_mgr = DeviceManager::new(&mut restored_res);
+            let bus: Arc<Mutex<dyn Device>> =
+                Arc::new(Mutex::new(BusDevice::new("bus".to_string())));
+            let dev: Arc<Mutex<dyn Device>> =
+                Arc::new(Mutex::new(BusDevice::new("dev".to_string())));
+            dev_mgr.restore_state(
+                &state,
+                vec![DeviceHandle::Exclusive(dev), DeviceHandle::Exclusive(bus)],
+            )?;
+
+            assert_eq!(dev_mgr.path_of("dev"), Some("/bus/dev".to_string()));
+            let descriptor = dev_mgr.device("dev").unwrap();
+            assert_eq!(descriptor.irq, Some(IrqResource::Legacy(Some(5))));
+            let addr = descriptor.resource[1].addr.unwrap();
+            let mut data = [0u8; 1];
+            dev_mgr.read(addr, &mut data, IoType::Mmio)?;
+            assert_eq!(data[0], 0x42);
+            assert_eq!(dev_mgr.save_state().serialize(), saved);
+        }
+
+        // Both allocators keep handing out the same resources.
+        assert_eq!(
+            restored_res.allocate_mmio_addresses(None, 0x1000),
+            sys_res.allocate_mmio_addresses(None, 0x1000)
+        );
+        assert_eq!(restored_res.allocate_irq(), sys_res.allocate_irq());
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_missing_device() {
+        let mut sys_res = system_allocator();
+        let state = {
+            let mut dev_mgr = DeviceManager::new(&mut sys_res);
+            let dev = Arc::new(Mutex::new(BusDevice::new("dev".to_string())));
+            let mut dev_req = dev.lock().unwrap().get_resource();
+            dev_mgr
+                .register_device(dev, None, &mut dev_req, None)
+                .unwrap();
+            dev_mgr.save_state()
+        };
+
+        let mut restored_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut restored_res);
+        let other: Arc<Mutex<dyn Device>> =
+            Arc::new(Mutex::new(BusDevice::new("other".to_string())));
+        match dev_mgr.restore_state(&state, vec![DeviceHandle::Exclusive(other)]) {
+            Err(Error::InvalidSnapshot) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(dev_mgr.device("dev").is_none());
+    }
+
+    #[test]
+    fn test_restore_failure() -> Result<()> {
+        #[derive(Default)]
+        struct Mapper {
+            mapped: Mutex<Vec<GuestAddress>>,
+        }
+
+        impl MemoryMapper for Mapper {
+            fn map(
+                &self,
+                addr: GuestAddress,
+                _size: GuestUsize,
+                _backing: &MemoryBacking,
+            ) -> std::io::Result<()> {
+                self.mapped.lock().unwrap().push(addr);
+                Ok(())
+            }
+            fn unmap(&self, addr: GuestAddress, _size: GuestUsize) -> std::io::Result<()> {
+                self.mapped.lock().unwrap().retain(|m| *m != addr);
+                Ok(())
+            }
+        }
+
+        let mut sys_res = system_allocator();
+        sys_res.reserve_shared_irqs(&[10]).unwrap();
+        let mut state = {
+            let mut dev_mgr = DeviceManager::new(&mut sys_res);
+            dev_mgr.set_memory_mapper(Arc::new(Mapper::default()));
+            dev_mgr.set_interrupt_backend(Arc::new(InterruptRecorder::default()))?;
+            for name in &["mem", "dev"] {
+                let dev = Arc::new(Mutex::new(BusDevice::new(name.to_string())));
+                let mut res_req = vec![IoResource::new(None, 0x1000, IoType::PhysicalMmio)];
+                dev_mgr.register_device(
+                    dev,
+                    None,
+                    &mut res_req,
+                    Some(IrqResource::SharedLegacy(None)),
+                )?;
+            }
+            dev_mgr.save_state()
+        };
+        let devices = || {
+            ["mem", "dev"]
+                .iter()
+                .map(|name| {
+                    let dev: Arc<Mutex<dyn Device>> =
+                        Arc::new(Mutex::new(BusDevice::new(name.to_string())));
+                    DeviceHandle::Exclusive(dev)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut restored_res = system_allocator();
+        restored_res.reserve_shared_irqs(&[10]).unwrap();
+        let initial = restored_res.save_state();
+        let mut dev_mgr = DeviceManager::new(&mut restored_res);
+        let mapper = Arc::new(Mapper::default());
+        dev_mgr.set_memory_mapper(mapper.clone());
+        dev_mgr.set_interrupt_backend(Arc::new(InterruptRecorder::default()))?;
+
+        // Devices without saved state are rejected before anything happens.
+        let mut extra = devices();
+        let other: Arc<Mutex<dyn Device>> =
+            Arc::new(Mutex::new(BusDevice::new("other".to_string())));
+        extra.push(DeviceHandle::Exclusive(other));
+        match dev_mgr.restore_state(&state, extra) {
+            Err(Error::InvalidSnapshot) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(dev_mgr.resource.save_state(), initial);
+
+        // A device failing to restore its state undoes the restored ones.
+        let idx = state.devices.len() - 1;
+        let failing = state.devices[idx].name.clone();
+        let saved = state.devices[idx].state.take();
+        state.devices[idx].state = Some((2, Vec::new()));
+        match dev_mgr.restore_state(&state, devices()) {
+            Err(Error::RestoreDevice { ref name, .. }) if *name == failing => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(mapper.mapped.lock().unwrap().is_empty());
+        assert!(dev_mgr.irq_lines.is_empty());
+        assert_eq!(dev_mgr.resource.save_state(), initial);
+        assert!(dev_mgr.device(&state.devices[0].name).is_none());
+
+        state.devices[idx].state = saved;
+        dev_mgr.restore_state(&state, devices())?;
+        assert_eq!(mapper.mapped.lock().unwrap().len(), 2);
+        assert_eq!(dev_mgr.resource.save_state(), state.allocator);
+        Ok(())
+    }
+
+    #[test]
+    fn test_msi_allocation() -> Result<()> {
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let msi = Arc::new(Mutex::new(BusDevice::new("msi".to_string())));
+        dev_mgr.register_device(
+            msi,
+            None,
+            &mut Vec::new(),
+            Some(IrqResource::Msi {
+                base: None,
+                count: 4,
+            }),
+        )?;
+        assert_eq!(
+            dev_mgr.device("msi").unwrap().irq,
+            Some(IrqResource::Msi {
+                base: Some(5),
+                count: 4
+            })
+        );
+
+        let msix = Arc::new(Mutex::new(BusDevice::new("msix".to_string())));
+        dev_mgr.register_device(
+            msix,
+            None,
+            &mut Vec::new(),
+            Some(IrqResource::MsiX {
+                base: None,
+                count: 3,
+            }),
+        )?;
+        assert_eq!(
+            dev_mgr.device("msix").unwrap().irq,
+            Some(IrqResource::MsiX {
+                base: Some(9),
+                count: 3
+            })
+        );
+
+        // MSI vector counts must be a power of two.
+        let bad = Arc::new(Mutex::new(BusDevice::new("bad".to_string())));
+        match dev_mgr.register_device(
+            bad,
+            None,
+            &mut Vec::new(),
+            Some(IrqResource::Msi {
+                base: None,
+                count: 3,
+            }),
+        ) {
+            Err(Error::AllocateIrq) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupt_delivery() -> Result<()> {
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let recorder = Arc::new(InterruptRecorder::default());
+        dev_mgr.set_interrupt_backend(recorder.clone())?;
+
+        let dev = Arc::new(Mutex::new(BusDevice::new("dev".to_string())));
+        dev_mgr.register_device(
+            dev.clone(),
+            None,
+            &mut Vec::new(),
+            Some(IrqResource::Legacy(None)),
+        )?;
+        let msix = Arc::new(Mutex::new(BusDevice::new("msix".to_string())));
+        dev_mgr.register_device(
+            msix.clone(),
+            None,
+            &mut Vec::new(),
+            Some(IrqResource::MsiX {
+                base: None,
+                count: 2,
+            }),
+        )?;
+        let quiet = Arc::new(Mutex::new(BusDevice::new("quiet".to_string())));
+        dev_mgr.register_device(quiet.clone(), None, &mut Vec::new(), None)?;
+        assert!(quiet.lock().unwrap().interrupt.is_none());
+
+        let line = dev.lock().unwrap().interrupt.clone().unwrap();
+        line.assert(0).unwrap();
+        line.deassert(0).unwrap();
+        let vectors = msix.lock().unwrap().interrupt.clone().unwrap();
+        assert_eq!(vectors.len(), 2);
+        vectors.trigger(1).unwrap();
+        assert_eq!(
+            recorder.take_events(),
+            vec![
+                InterruptEvent::Level(5, true),
+                InterruptEvent::Level(5, false),
+                InterruptEvent::Edge(7)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixed_irq() -> Result<()> {
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let uart = Arc::new(Mutex::new(BusDevice::new("uart".to_string())));
+        dev_mgr.register_device(
+            uart.clone(),
+            None,
+            &mut Vec::new(),
+            Some(IrqResource::Legacy(Some(4))),
+        )?;
+        assert_eq!(
+            dev_mgr.device("uart").unwrap().irq,
+            Some(IrqResource::Legacy(Some(4)))
+        );
+
+        let other = Arc::new(Mutex::new(BusDevice::new("other".to_string())));
+        match dev_mgr.register_device(
+            other.clone(),
+            None,
+            &mut Vec::new(),
+            Some(IrqResource::Legacy(Some(4))),
+        ) {
+            Err(Error::IrqConflict(4)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // Unregistering a device frees its IRQ.
+        dev_mgr.unregister_device(uart)?;
+        dev_mgr.register_device(
+            other,
+            None,
+            &mut Vec::new(),
+            Some(IrqResource::Legacy(Some(4))),
+        )?;
+        let dev = Arc::new(Mutex::new(BusDevice::new("dev".to_string())));
+        dev_mgr.register_device(
+            dev.clone(),
+            None,
+            &mut Vec::new(),
+            Some(IrqResource::Legacy(None)),
+        )?;
+        dev_mgr.unregister_device(dev)?;
+        assert_eq!(dev_mgr.resource.allocate_irq(), Some(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_irq() -> Result<()> {
+        let mut sys_res = system_allocator();
+        sys_res.reserve_shared_irqs(&[10, 11]).unwrap();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let recorder = Arc::new(InterruptRecorder::default());
+        dev_mgr.set_interrupt_backend(recorder.clone())?;
+
+        let mut devs = Vec::new();
+        for name in &["00:01.0", "00:02.0", "00:03.0"] {
+            let dev = Arc::new(Mutex::new(BusDevice::new(name.to_string())));
+            dev_mgr.register_device(
+                dev.clone(),
+                None,
+                &mut Vec::new(),
+                Some(IrqResource::SharedLegacy(None)),
+            )?;
+            devs.push(dev);
+        }
+        let irqs: Vec<_> = devs
+            .iter()
+            .map(|dev| {
+                let name = dev.lock().unwrap().name.clone();
+                dev_mgr.device(&name).unwrap().irq
+            })
+            .collect();
+        assert_eq!(
+            irqs,
+            vec![
+                Some(IrqResource::SharedLegacy(Some(10))),
+                Some(IrqResource::SharedLegacy(Some(11))),
+                Some(IrqResource::SharedLegacy(Some(10)))
+            ]
+        );
+
+        // Shared lines can not be allocated exclusively.
+        let uart = Arc::new(Mutex::new(BusDevice::new("uart".to_string())));
+        match dev_mgr.register_device(
+            uart,
+            None,
+            &mut Vec::new(),
+            Some(IrqResource::Legacy(Some(10))),
+        ) {
+            Err(Error::IrqConflict(10)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let first = devs[0].lock().unwrap().interrupt.clone().unwrap();
+        let third = devs[2].lock().unwrap().interrupt.clone().unwrap();
+        first.assert(0).unwrap();
+        third.assert(0).unwrap();
+        first.deassert(0).unwrap();
+        assert_eq!(
+            recorder.take_events(),
+            vec![InterruptEvent::Level(10, true)]
+        );
+        third.deassert(0).unwrap();
+        assert_eq!(
+            recorder.take_events(),
+            vec![InterruptEvent::Level(10, false)]
+        );
+
+        // The backend of the shared lines can not change under their devices.
+        match dev_mgr.set_interrupt_backend(recorder.clone()) {
+            Err(Error::Exist) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        let mut devs = devs.into_iter();
+        dev_mgr.unregister_device(devs.next().unwrap())?;
+        dev_mgr.unregister_device(devs.next().unwrap())?;
+        assert_eq!(dev_mgr.irq_lines.keys().collect::<Vec<_>>(), vec![&10]);
+        dev_mgr.unregister_device(devs.next().unwrap())?;
+        assert!(dev_mgr.irq_lines.is_empty());
+        assert_eq!(
+            dev_mgr.resource.save_state().shared_irqs,
+            vec![(10, 0), (11, 0)]
+        );
+        dev_mgr.set_interrupt_backend(recorder)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_doorbells() -> Result<()> {
+        struct Notify {
+            doorbells: Vec<Doorbell>,
+            writes: usize,
+        }
+
+        impl Device for Notify {
+            fn name(&self) -> String {
+                "notify".to_string()
+            }
+            fn read(
+                &mut self,
+                _res_index: usize,
+                _offset: GuestUsize,
+                _data: &mut [u8],
+                _io_type: IoType,
+            ) -> device::Result<()> {
+                Ok(())
+            }
+            fn write(
+                &mut self,
+                _res_index: usize,
+                _offset: GuestUsize,
+                _data: &[u8],
+                _io_type: IoType,
+            ) -> device::Result<()> {
+                self.writes += 1;
+                Ok(())
+            }
+            fn set_resources(&mut self, _res: &[IoResource], _irq: Option<IrqResource>) {}
+            fn doorbells(&self) -> Vec<Doorbell> {
+                self.doorbells.clone()
+            }
+        }
+
+        let event = Arc::new(EventFd::new(EFD_NONBLOCK).unwrap());
+        let notify = Arc::new(Mutex::new(Notify {
+            doorbells: vec![
+                Doorbell::new(1, 0x50, 4, Some(1), event.clone()),
+                // Out of the resource.
+                Doorbell::new(1, 0x1000, 4, None, event.clone()),
+            ],
+            writes: 0,
+        }));
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let mut res_req = vec![
+            IoResource::new(Some(GuestAddress(0xcf8)), 8, IoType::Pio),
+            IoResource::new(None, 0x1000, IoType::Mmio),
+        ];
+        dev_mgr.register_device(notify.clone(), None, &mut res_req, None)?;
+
+        let ioevents = dev_mgr.ioevents();
+        assert_eq!(ioevents.len(), 1);
+        let addr = GuestAddress(res_req[1].addr.unwrap().0 + 0x50);
+        assert_eq!(ioevents[0].addr, addr);
+        assert_eq!(ioevents[0].io_type, IoType::Mmio);
+        assert_eq!(ioevents[0].datamatch, Some(1));
+
+        // Without ioeventfd, matching writes signal the event on the exit path.
+        dev_mgr.write(addr, &[1, 0, 0, 0], IoType::Mmio)?;
+        assert_eq!(event.read().unwrap(), 1);
+        assert_eq!(notify.lock().unwrap().writes, 0);
+        dev_mgr.write(addr, &[2, 0, 0, 0], IoType::Mmio)?;
+        assert_eq!(notify.lock().unwrap().writes, 1);
+        assert!(event.read().is_err());
+
+        dev_mgr.unregister_device(notify)?;
+        assert!(dev_mgr.ioevents().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_physical_mmio() -> Result<()> {
+        #[derive(Default)]
+        struct Mapper {
+            mapped: Mutex<Vec<(GuestAddress, GuestUsize, usize)>>,
+        }
+
+        impl MemoryMapper for Mapper {
+            fn map(
+                &self,
+                addr: GuestAddress,
+                size: GuestUsize,
+                backing: &MemoryBacking,
+            ) -> std::io::Result<()> {
+                if let MemoryBacking::Host { addr: host } = *backing {
+                    self.mapped.lock().unwrap().push((addr, size, host));
+                }
+                Ok(())
+            }
+            fn unmap(&self, addr: GuestAddress, _size: GuestUsize) -> std::io::Result<()> {
+                self.mapped.lock().unwrap().retain(|m| m.0 != addr);
+                Ok(())
+            }
+        }
+
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let mapper = Arc::new(Mapper::default());
+        dev_mgr.set_memory_mapper(mapper.clone());
+
+        let bar = Arc::new(Mutex::new(BusDevice::new("bar".to_string())));
+        let mut res_req = vec![
+            IoResource::new(None, 0x2000, IoType::PhysicalMmio),
+            IoResource::new(None, 0x1000, IoType::Mmio),
+            IoResource::new(None, 0x1000, IoType::PhysicalMmio),
+        ];
+        dev_mgr.register_device(bar.clone(), None, &mut res_req, None)?;
+        assert_eq!(
+            *mapper.mapped.lock().unwrap(),
+            vec![
+                (res_req[0].addr.unwrap(), 0x2000, 0x7f00_0000),
+                (res_req[2].addr.unwrap(), 0x1000, 0x7f02_0000)
+            ]
+        );
+        // Mapped memory does not trap.
+        let mut data = [0u8; 1];
+        assert!(dev_mgr
+            .read(res_req[0].addr.unwrap(), &mut data, IoType::PhysicalMmio)
+            .is_err());
+
+        dev_mgr.unregister_device(bar)?;
+        assert!(mapper.mapped.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_rollback() -> Result<()> {
+        struct Mapper;
+
+        impl MemoryMapper for Mapper {
+            fn map(
+                &self,
+                _addr: GuestAddress,
+                _size: GuestUsize,
+                _backing: &MemoryBacking,
+            ) -> std::io::Result<()> {
+                Err(std::io::Error::other("no slot"))
+            }
+            fn unmap(&self, _addr: GuestAddress, _size: GuestUsize) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let dev = Arc::new(Mutex::new(BusDevice::new("dev".to_string())));
+        let mut res_req = vec![
+            IoResource::new(Some(GuestAddress(0x3f8)), 8, IoType::Pio),
+            IoResource::new(Some(GuestAddress(0x10)), 0x1000, IoType::PciConfig),
+        ];
+        dev_mgr.register_device(
+            dev.clone(),
+            None,
+            &mut res_req,
+            Some(IrqResource::Legacy(None)),
+        )?;
+        let state = dev_mgr.resource.save_state();
+        let request = vec![
+            IoResource::new(None, 0x1000, IoType::Mmio),
+            IoResource::new(Some(GuestAddress(0x3f8)), 8, IoType::Pio),
+        ];
+        let failed = |dev_mgr: &DeviceManager, res_req: &[IoResource], request: &[IoResource]| {
+            let mut data = [0u8; 1];
+            assert_eq!(dev_mgr.resource.save_state(), state);
+            assert_eq!(res_req, request);
+            assert_eq!(dev_mgr.devices.len(), 1);
+            // The registered device is still reachable.
+            dev_mgr
+                .read(GuestAddress(0x3f8), &mut data, IoType::Pio)
+                .unwrap();
+        };
+
+        // Duplicate name.
+        let other = Arc::new(Mutex::new(BusDevice::new("dev".to_string())));
+        let mut res_req = vec![IoResource::new(None, 0x1000, IoType::Mmio)];
+        match dev_mgr.register_device(other, None, &mut res_req, None) {
+            Err(Error::Exist) => (),
+            _ => panic!("duplicate name registered"),
+        }
+        failed(&dev_mgr, &res_req, &request[..1]);
+
+        // Resource allocation.
+        let other = Arc::new(Mutex::new(BusDevice::new("other".to_string())));
+        let mut res_req = request.clone();
+        match dev_mgr.register_device(other.clone(), None, &mut res_req, None) {
+            Err(Error::Overlap) => (),
+            _ => panic!("overlapping resource allocated"),
+        }
+        failed(&dev_mgr, &res_req, &request);
+
+        // PIO resources are not allocated without an address.
+        let mut res_req = vec![request[0], IoResource::new(None, 8, IoType::Pio)];
+        let expected = res_req.clone();
+        match dev_mgr.register_device(
+            other.clone(),
+            None,
+            &mut res_req,
+            Some(IrqResource::Legacy(None)),
+        ) {
+            Err(Error::NonePIOAddress) => (),
+            _ => panic!("PIO resource allocated without address"),
+        }
+        failed(&dev_mgr, &res_req, &expected);
+
+        // IRQ allocation.
+        let mut res_req = request[..1].to_vec();
+        match dev_mgr.register_device(
+            other.clone(),
+            None,
+            &mut res_req,
+            Some(IrqResource::Legacy(Some(5))),
+        ) {
+            Err(Error::IrqConflict(5)) => (),
+            _ => panic!("conflicting irq allocated"),
+        }
+        failed(&dev_mgr, &res_req, &request[..1]);
+
+        // Bus insertion, PCI configuration space addresses are not allocated.
+        let mut res_req = vec![
+            request[0],
+            IoResource::new(Some(GuestAddress(0x10)), 0x1000, IoType::PciConfig),
+        ];
+        let expected = res_req.clone();
+        match dev_mgr.register_device(
+            other.clone(),
+            None,
+            &mut res_req,
+            Some(IrqResource::Legacy(None)),
+        ) {
+            Err(Error::Overlap) => (),
+            _ => panic!("overlapping range inserted"),
+        }
+        failed(&dev_mgr, &res_req, &expected);
+
+        // Memory mapping.
+        dev_mgr.set_memory_mapper(Arc::new(Mapper));
+        let mut res_req = vec![
+            request[0],
+            IoResource::new(None, 0x1000, IoType::PhysicalMmio),
+        ];
+        let expected = res_req.clone();
+        match dev_mgr.register_device(
+            other.clone(),
+            None,
+            &mut res_req,
+            Some(IrqResource::Legacy(None)),
+        ) {
+            Err(Error::MapMemory { .. }) => (),
+            _ => panic!("unmapped memory registered"),
+        }
+        failed(&dev_mgr, &res_req, &expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_relocate_resource() -> Result<()> {
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let dev = Arc::new(Mutex::new(BusDevice::new("dev".to_string())));
+        let mut res_req = vec![
+            IoResource::new(Some(GuestAddress(0xcf8)), 8, IoType::Pio),
+            IoResource::new(None, 0x1000, IoType::Mmio),
+        ];
+        dev_mgr.register_device(dev.clone(), None, &mut res_req, None)?;
+        let other = Arc::new(Mutex::new(BusDevice::new("other".to_string())));
+        let mut other_req = vec![IoResource::new(None, 0x1000, IoType::Mmio)];
+        dev_mgr.register_device(other, None, &mut other_req, None)?;
+
+        let old = res_req[1].addr.unwrap();
+        let new = GuestAddress(0x1000_1000);
+        let handle = DeviceHandle::Exclusive(dev.clone());
+        dev_mgr.relocate_resource(handle.clone(), 1, new)?;
+        assert_eq!(dev_mgr.device("dev").unwrap().resource[1].addr, Some(new));
+
+        let mut data = [0u8; 1];
+        dev_mgr.read(GuestAddress(0x1000_1010), &mut data, IoType::Mmio)?;
+        assert_eq!(dev.lock().unwrap().last_access, Some((1, 0x10)));
+        assert!(dev_mgr.read(old, &mut data, IoType::Mmio).is_err());
+
+        // The target range must be free.
+        match dev_mgr.relocate_resource(handle.clone(), 1, other_req[0].addr.unwrap()) {
+            Err(Error::Overlap) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(dev_mgr.relocate_resource(handle.clone(), 2, old).is_err());
+
+        // The old range got freed.
+        dev_mgr.relocate_resource(handle, 0, GuestAddress(0x3f8))?;
+        dev_mgr.read(GuestAddress(0x3f8), &mut data, IoType::Pio)?;
+        assert!(dev_mgr
+            .read(GuestAddress(0xcf8), &mut data, IoType::Pio)
+            .is_err());
+        assert_eq!(
+            dev_mgr.resource.allocate_mmio_addresses(Some(old), 0x1000),
+            Some(old)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_address_spaces() -> Result<()> {
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let dma = dev_mgr.add_address_space("iommu0")?;
+        assert_eq!(dev_mgr.address_space("iommu0"), Some(dma));
+        assert!(dev_mgr.add_address_space("iommu0").is_err());
+
+        let dev = Arc::new(Mutex::new(BusDevice::new("dev".to_string())));
+        let config = GuestAddress(0x18 << 12);
+        let mut res_req = vec![
+            IoResource::new(Some(config), 0x1000, IoType::PciConfig),
+            IoResource::new(Some(GuestAddress(0x1000)), 0x1000, dma),
+        ];
+        dev_mgr.register_device(dev.clone(), None, &mut res_req, None)?;
+
+        let mut data = [0u8; 4];
+        dev_mgr.read(GuestAddress(config.0 + 0x10), &mut data, IoType::PciConfig)?;
+        assert_eq!(dev.lock().unwrap().last_access, Some((0, 0x10)));
+        dev_mgr.write(GuestAddress(0x1008), &data, dma)?;
+        assert_eq!(dev.lock().unwrap().last_access, Some((1, 8)));
+        // Address spaces are independent.
+        assert!(dev_mgr
+            .read(GuestAddress(0x1008), &mut data, IoType::Mmio)
+            .is_err());
+
+        let other = Arc::new(Mutex::new(BusDevice::new("other".to_string())));
+        match dev_mgr.register_device(
+            other.clone(),
+            None,
+            &mut vec![IoResource::new(None, 0x1000, IoType::PciConfig)],
+            None,
+        ) {
+            Err(Error::NoneAddress) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match dev_mgr.register_device(
+            other.clone(),
+            None,
+            &mut vec![IoResource::new(Some(config), 0x1000, IoType::Custom(1))],
+            None,
+        ) {
+            Err(Error::InvalidAddressSpace) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        // Ranges placed by the caller cannot wrap past the end of the address
+        // space, nor partially overlap.
+        let wrapping = IoResource::new(Some(GuestAddress(u64::MAX - 1)), 0x10, dma);
+        let mut wrapping_req = vec![wrapping];
+        match dev_mgr.register_device(other.clone(), None, &mut wrapping_req, None) {
+            Err(Error::Oversize) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(wrapping_req, vec![wrapping]);
+        match dev_mgr.register_device(
+            other,
+            None,
+            &mut vec![IoResource::new(Some(GuestAddress(0x800)), 0x1000, dma)],
+            None,
+        ) {
+            Err(Error::Overlap) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        dev_mgr.unregister_device(dev)?;
+        assert!(dev_mgr.read(config, &mut data, IoType::PciConfig).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_container_ranges() -> Result<()> {
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let mut host = BusDevice::new("pci0".to_string());
+        host.containers.push(0);
+        let host = Arc::new(Mutex::new(host));
+        let hole = GuestAddress(0x1800_0000);
+        let mut host_req = vec![IoResource::new(Some(hole), 0x100_0000, IoType::Mmio)];
+        dev_mgr.register_device(host.clone(), None, &mut host_req, None)?;
+
+        // The child range is carved out of the container, not allocated.
+        let ecam = Arc::new(Mutex::new(BusDevice::new("ecam".to_string())));
+        let bus: Arc<Mutex<dyn Device>> = host.clone();
+        let mut ecam_req = vec![IoResource::new(Some(hole), 0x10_0000, IoType::Mmio)];
+        dev_mgr.register_device(ecam.clone(), Some(bus.clone()), &mut ecam_req, None)?;
+        let other = Arc::new(Mutex::new(BusDevice::new("other".to_string())));
+        let mut other_req = vec![IoResource::new(
+            Some(GuestAddress(0x1810_0000)),
+            0x1000,
+            IoType::Mmio,
+        )];
+        match dev_mgr.register_device(other, None, &mut other_req, None) {
+            Err(Error::Overlap) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let mut data = [0u8; 4];
+        dev_mgr.read(GuestAddress(0x1800_0010), &mut data, IoType::Mmio)?;
+        assert_eq!(ecam.lock().unwrap().last_access, Some((0, 0x10)));
+        dev_mgr.read(GuestAddress(0x1820_0000), &mut data, IoType::Mmio)?;
+        assert_eq!(host.lock().unwrap().last_access, Some((0, 0x20_0000)));
+
+        // Children move within the container, which stays in place.
+        let moved = GuestAddress(0x1840_0000);
+        match dev_mgr.relocate_resource(DeviceHandle::Exclusive(bus.clone()), 0, moved) {
+            Err(Error::ChildrenExist) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        let ecam_handle = DeviceHandle::Exclusive(ecam.clone());
+        dev_mgr.relocate_resource(ecam_handle.clone(), 0, moved)?;
+        dev_mgr.read(GuestAddress(0x1840_0004), &mut data, IoType::Mmio)?;
+        assert_eq!(ecam.lock().unwrap().last_access, Some((0, 4)));
+        dev_mgr.read(hole, &mut data, IoType::Mmio)?;
+        assert_eq!(host.lock().unwrap().last_access, Some((0, 0)));
+
+        // Aliases route to an offset of a device resource.
+        let vga = Range(GuestAddress(0xa_0000), 0x1000);
+        dev_mgr.add_alias(vga, IoType::Mmio, ecam_handle.clone(), 0, 0x1000)?;
+        dev_mgr.read(GuestAddress(0xa_0004), &mut data, IoType::Mmio)?;
+        assert_eq!(ecam.lock().unwrap().last_access, Some((0, 0x1004)));
+        match dev_mgr.add_alias(vga, IoType::Pio, ecam_handle.clone(), 0, 0x10_0000) {
+            Err(Error::Oversize) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(dev_mgr
+            .add_alias(vga, IoType::Mmio, ecam_handle.clone(), 1, 0)
+            .is_err());
+        assert!(dev_mgr
+            .remove_alias(GuestAddress(0xa_0000), IoType::Pio)
+            .is_err());
+
+        // The aliases go along with the device, the container keeps the range.
+        dev_mgr.unregister_device(ecam)?;
+        assert!(dev_mgr
+            .read(GuestAddress(0xa_0004), &mut data, IoType::Mmio)
+            .is_err());
+        assert!(dev_mgr.remove_alias(vga.0, IoType::Mmio).is_err());
+        assert!(dev_mgr
+            .resource
+            .allocate_mmio_addresses(Some(moved), 0x1000)
+            .is_none());
+        dev_mgr.unregister_device(host)?;
+        assert_eq!(
+            dev_mgr
+                .resource
+                .allocate_mmio_addresses(Some(moved), 0x1000),
+            Some(moved)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unclaimed_accesses() -> Result<()> {
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let mut data = [0x5au8; 2];
+        match dev_mgr.read(GuestAddress(0x80), &mut data, IoType::Pio) {
+            Err(Error::NonExist) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(data, [0x5a; 2]);
+
+        dev_mgr.set_fallback(IoType::Pio, Fallback::AllOnes)?;
+        dev_mgr.read(GuestAddress(0x80), &mut data, IoType::Pio)?;
+        assert_eq!(data, [0xff; 2]);
+        dev_mgr.write(GuestAddress(0x80), &data, IoType::Pio)?;
+        dev_mgr.set_fallback(IoType::Mmio, Fallback::Zeros)?;
+        dev_mgr.read(GuestAddress(0x1000_0000), &mut data, IoType::Mmio)?;
+        assert_eq!(data, [0; 2]);
+
+        // The catch-all device sees the guest address as offset.
+        let dma = dev_mgr.add_address_space("iommu0")?;
+        let catch_all = Arc::new(Mutex::new(BusDevice::new("catch-all".to_string())));
+        let handle = DeviceHandle::Exclusive(catch_all.clone());
+        dev_mgr.set_fallback(dma, Fallback::Forward(handle))?;
+        dev_mgr.write(GuestAddress(0x2000), &data, dma)?;
+        assert_eq!(catch_all.lock().unwrap().last_access, Some((0, 0x2000)));
+
+        dev_mgr.set_fallback(IoType::PciConfig, Fallback::Record)?;
+        assert!(dev_mgr
+            .write(GuestAddress(0x8000), &[1, 2], IoType::PciConfig)
+            .is_err());
+        assert!(dev_mgr
+            .read(GuestAddress(0x8000), &mut data, IoType::PciConfig)
+            .is_err());
+        let log = dev_mgr.take_unclaimed_log();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].data, Some(vec![1, 2]));
+        assert_eq!(
+            (log[1].addr, log[1].size, log[1].data.clone()),
+            (GuestAddress(0x8000), 2, None)
+        );
+        assert!(dev_mgr.take_unclaimed_log().is_empty());
+
+        // Claimed accesses are not counted.
+        let dev = Arc::new(Mutex::new(BusDevice::new("dev".to_string())));
+        let mut res_req = vec![IoResource::new(Some(GuestAddress(0x3f8)), 8, IoType::Pio)];
+        dev_mgr.register_device(dev, None, &mut res_req, None)?;
+        dev_mgr.read(GuestAddress(0x3f8), &mut data, IoType::Pio)?;
+        assert_eq!(
+            dev_mgr.unclaimed_accesses(),
+            vec![
+                (IoType::Pio, GuestAddress(0x80), 3),
+                (IoType::Mmio, GuestAddress(0x1000_0000), 1),
+                (IoType::PciConfig, GuestAddress(0x8000), 2),
+                (dma, GuestAddress(0x2000), 1),
+            ]
+        );
+
+        // The guest can not grow the counts and the log without bounds.
+        for i in 0..MAX_UNCLAIMED_LOG as u64 + 1 {
+            let _ = dev_mgr.write(GuestAddress(0x1_0000 + i), &[1], IoType::PciConfig);
+        }
+        let log = dev_mgr.take_unclaimed_log();
+        assert_eq!(log.len(), MAX_UNCLAIMED_LOG);
+        assert_eq!(log[0].addr, GuestAddress(0x1_0001));
+        assert_eq!(dev_mgr.unclaimed_accesses().len(), MAX_UNCLAIMED_ADDRESSES);
+
+        assert!(dev_mgr
+            .set_fallback(IoType::PhysicalMmio, Fallback::Zeros)
+            .is_err());
+        assert!(dev_mgr
+            .set_fallback(IoType::Custom(1), Fallback::Zeros)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_spanning_accesses() -> Result<()> {
+        let mut sys_res = system_allocator();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let space = dev_mgr.add_address_space("space")?;
+        let a = Arc::new(Mutex::new(BusDevice::new("a".to_string())));
+        let mut a_req = vec![IoResource::new(
+            Some(GuestAddress(0x1000_1000)),
+            0x10,
+            space,
+        )];
+        dev_mgr.register_device(a.clone(), None, &mut a_req, None)?;
+        let b = Arc::new(Mutex::new(BusDevice::new("b".to_string())));
+        let mut b_req = vec![IoResource::new(
+            Some(GuestAddress(0x1000_1010)),
+            0x10,
+            space,
+        )];
+        dev_mgr.register_device(b.clone(), None, &mut b_req, None)?;
+
+        let mut data = [0u8; 8];
+        match dev_mgr.read(GuestAddress(0x1000_100c), &mut data, space) {
+            Err(Error::SpanningAccess { size: 8, .. }) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(a.lock().unwrap().last_access.is_none());
+        dev_mgr.read(GuestAddress(0x1000_1008), &mut data, space)?;
+        assert_eq!(a.lock().unwrap().last_access, Some((0, 8)));
+
+        // Each device gets its part of the access.
+        dev_mgr.set_span_policy(space, SpanPolicy::Split)?;
+        dev_mgr.read(GuestAddress(0x1000_100c), &mut data, space)?;
+        assert_eq!(data, [0, 0x10, 0, 0, 0, 0x10, 0, 0]);
+        assert_eq!(a.lock().unwrap().last_access, Some((0, 0xc)));
+        assert_eq!(b.lock().unwrap().last_access, Some((0, 0)));
+        // No part is written when the fallback fails the unclaimed one.
+        match dev_mgr.write(GuestAddress(0x1000_101e), &[1, 2, 3, 4], space) {
+            Err(Error::NonExist) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(b.lock().unwrap().last_access, Some((0, 0)));
+        assert_eq!(b.lock().unwrap().config_address, 0x1000);
+        dev_mgr.set_fallback(space, Fallback::AllOnes)?;
+        dev_mgr.write(GuestAddress(0x1000_101e), &[1, 2, 3, 4], space)?;
+        assert_eq!(b.lock().unwrap().last_access, Some((0, 0xe)));
+        assert_eq!(b.lock().unwrap().config_address, 1);
+
+        // Only the first device sees the access, the rest is unclaimed.
+        dev_mgr.set_span_policy(space, SpanPolicy::Clip)?;
+        dev_mgr.read(GuestAddress(0x1000_1004), &mut data, space)?;
+        assert_eq!(a.lock().unwrap().last_access, Some((0, 4)));
+        dev_mgr.read(GuestAddress(0x1000_100c), &mut data, space)?;
+        assert_eq!(data, [0, 0x10, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(b.lock().unwrap().last_access, Some((0, 0xe)));
+        assert_eq!(
+            dev_mgr.unclaimed_accesses(),
+            vec![
+                (space, GuestAddress(0x1000_1010), 1),
+                (space, GuestAddress(0x1000_1020), 2),
+            ]
+        );
+
+        // A part failing in its device does not undo the earlier ones.
+        struct ReadOnly;
+
+        impl Device for ReadOnly {
+            fn name(&self) -> String {
+                "ro".to_string()
+            }
+            fn read(
+                &mut self,
+                _res_index: usize,
+                _offset: GuestUsize,
+                _data: &mut [u8],
+                _io_type: IoType,
+            ) -> device::Result<()> {
+                Ok(())
+            }
+            fn write(
+                &mut self,
+                _res_index: usize,
+                offset: GuestUsize,
+                _data: &[u8],
+                _io_type: IoType,
+            ) -> device::Result<()> {
+                Err(device::Error::ReservedRegister(offset))
+            }
+            fn set_resources(&mut self, _res: &[IoResource], _irq: Option<IrqResource>) {}
+        }
+
+        let mut ro_req = vec![IoResource::new(
+            Some(GuestAddress(0x1000_1020)),
+            0x10,
+            space,
+        )];
+        dev_mgr.register_device(Arc::new(Mutex::new(ReadOnly)), None, &mut ro_req, None)?;
+        dev_mgr.set_span_policy(space, SpanPolicy::Split)?;
+        match dev_mgr.write(GuestAddress(0x1000_101c), &[7, 0, 0, 0, 8, 0, 0, 0], space) {
+            Err(Error::DeviceAccess { ref name, .. }) if name == "ro" => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(b.lock().unwrap().last_access, Some((0, 0xc)));
+        assert_eq!(b.lock().unwrap().config_address, 7);
+
+        assert!(dev_mgr
+            .set_span_policy(IoType::PhysicalMmio, SpanPolicy::Split)
+            .is_err());
+        Ok(())
+    }
 }
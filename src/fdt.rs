@@ -0,0 +1,444 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Flattened device tree generation.
+//!
+//! The [FdtWriter](struct.FdtWriter.html) builds a DTB blob, version 17. The
+//! VMM writes the machine nodes, such as the CPUs, memory and interrupt
+//! controller, and lets `DeviceManager::write_fdt()` add one node per
+//! registered device. Devices complete their node, e.g. with its `compatible`
+//! property, through `Device::fdt_properties()`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::result;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+// Empty memory reservation block, made of its terminating entry.
+const FDT_RSVMAP_SIZE: usize = 16;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+/// Error type for device tree generation.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The node or property name is invalid.
+    InvalidName(String),
+    /// A property was written outside of any node.
+    PropertyOutsideNode(String),
+    /// A node was ended without being begun, or left open.
+    UnbalancedNode,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidName(name) => write!(f, "invalid device tree name {}", name),
+            Error::PropertyOutsideNode(name) => {
+                write!(f, "device tree property {} outside of any node", name)
+            }
+            Error::UnbalancedNode => write!(f, "unbalanced device tree node"),
+        }
+    }
+}
+
+/// Simplified result type for device tree generation.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Handler returning the `interrupts` cells of a legacy IRQ, given its number
+/// and whether it is a shared level triggered line.
+pub type FdtIrqCells = dyn Fn(u32, bool) -> Vec<u32>;
+
+/// Property of a device tree node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FdtProperty {
+    /// Property name.
+    pub name: String,
+    /// Raw property value.
+    pub value: Vec<u8>,
+}
+
+impl FdtProperty {
+    /// Build a FdtProperty struct.
+    pub fn new(name: &str, value: Vec<u8>) -> Self {
+        FdtProperty {
+            name: name.to_string(),
+            value,
+        }
+    }
+
+    /// Build a property without value.
+    pub fn empty(name: &str) -> Self {
+        FdtProperty::new(name, Vec::new())
+    }
+
+    /// Build a string property.
+    pub fn string(name: &str, value: &str) -> Self {
+        FdtProperty::strings(name, &[value])
+    }
+
+    /// Build a string list property.
+    pub fn strings(name: &str, values: &[&str]) -> Self {
+        let mut value = Vec::new();
+        for s in values.iter() {
+            value.extend_from_slice(s.as_bytes());
+            value.push(0);
+        }
+        FdtProperty::new(name, value)
+    }
+
+    /// Build a property of 32 bit cells.
+    pub fn cells(name: &str, values: &[u32]) -> Self {
+        let mut value = Vec::with_capacity(values.len() * 4);
+        for cell in values.iter() {
+            value.extend_from_slice(&cell.to_be_bytes());
+        }
+        FdtProperty::new(name, value)
+    }
+
+    /// Build a property of a single 32 bit cell.
+    pub fn u32(name: &str, value: u32) -> Self {
+        FdtProperty::cells(name, &[value])
+    }
+}
+
+/// Builder of a flattened device tree blob.
+#[derive(Default)]
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    /// Create an empty device tree, to be started with the root node `""`.
+    pub fn new() -> Self {
+        FdtWriter::default()
+    }
+
+    /// Begin the node `name`, as a child of the current node.
+    pub fn begin_node(&mut self, name: &str) -> Result<()> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || ",._+-@".contains(c);
+        if (self.depth == 0) != name.is_empty() || !name.chars().all(valid) {
+            return Err(Error::InvalidName(name.to_string()));
+        }
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// End the current node.
+    pub fn end_node(&mut self) -> Result<()> {
+        if self.depth == 0 {
+            return Err(Error::UnbalancedNode);
+        }
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+        Ok(())
+    }
+
+    /// Add a property to the current node.
+    pub fn property(&mut self, prop: &FdtProperty) -> Result<()> {
+        if prop.name.is_empty() || prop.name.contains('\0') {
+            return Err(Error::InvalidName(prop.name.clone()));
+        }
+        if self.depth == 0 {
+            return Err(Error::PropertyOutsideNode(prop.name.clone()));
+        }
+        let offset = self.string_offset(&prop.name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(prop.value.len() as u32);
+        self.push_u32(offset);
+        self.structure.extend_from_slice(&prop.value);
+        self.align();
+        Ok(())
+    }
+
+    /// Return the device tree blob, once every node got ended.
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        if self.depth != 0 || self.structure.is_empty() {
+            return Err(Error::UnbalancedNode);
+        }
+        self.push_u32(FDT_END);
+
+        let off_struct = FDT_HEADER_SIZE + FDT_RSVMAP_SIZE;
+        let off_strings = off_struct + self.structure.len();
+        let total_size = off_strings + self.strings.len();
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            off_struct as u32,
+            off_strings as u32,
+            FDT_HEADER_SIZE as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob = Vec::with_capacity(total_size);
+        for field in header.iter() {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; FDT_RSVMAP_SIZE]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        Ok(blob)
+    }
+
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while self.structure.len() & 3 != 0 {
+            self.structure.push(0);
+        }
+    }
+}
+
+/// Return `name` with the characters not allowed in node names replaced.
+pub(crate) fn node_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || ",._+-".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate vm_allocator;
+
+    use self::vm_allocator::SystemAllocator;
+    use super::*;
+    use crate::device;
+    use crate::device::{Device, IoResource, IoType, IrqResource};
+    use crate::device_manager::DeviceManager;
+    use std::sync::{Arc, Mutex};
+    use vm_memory::{GuestAddress, GuestUsize};
+
+    #[derive(Debug, Default, Eq, PartialEq)]
+    struct Node {
+        name: String,
+        properties: Vec<FdtProperty>,
+        children: Vec<Node>,
+    }
+
+    impl Node {
+        fn child(&self, name: &str) -> &Node {
+            self.children.iter().find(|n| n.name == name).unwrap()
+        }
+
+        fn property(&self, name: &str) -> Option<&[u8]> {
+            self.properties
+                .iter()
+                .find(|p| p.name == name)
+                .map(|p| &p.value[..])
+        }
+    }
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        let mut value = [0u8; 4];
+        value.copy_from_slice(&blob[offset..offset + 4]);
+        u32::from_be_bytes(value)
+    }
+
+    fn c_string(blob: &[u8], offset: usize) -> String {
+        let end = offset + blob[offset..].iter().position(|b| *b == 0).unwrap();
+        String::from_utf8(blob[offset..end].to_vec()).unwrap()
+    }
+
+    // Parse a blob back into its tree of nodes.
+    fn parse(blob: &[u8]) -> Node {
+        assert_eq!(be32(blob, 0), FDT_MAGIC);
+        assert_eq!(be32(blob, 4) as usize, blob.len());
+        let strings = be32(blob, 12) as usize;
+        let mut offset = be32(blob, 8) as usize;
+        let mut stack: Vec<Node> = Vec::new();
+        loop {
+            let token = be32(blob, offset);
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_string(blob, offset);
+                    offset += (name.len() + 4) & !3;
+                    stack.push(Node {
+                        name,
+                        ..Default::default()
+                    });
+                }
+                FDT_PROP => {
+                    let len = be32(blob, offset) as usize;
+                    let name = c_string(blob, strings + be32(blob, offset + 4) as usize);
+                    let value = blob[offset + 8..offset + 8 + len].to_vec();
+                    offset += 8 + ((len + 3) & !3);
+                    stack
+                        .last_mut()
+                        .unwrap()
+                        .properties
+                        .push(FdtProperty::new(&name, value));
+                }
+                FDT_END_NODE => {
+                    let node = stack.pop().unwrap();
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => {
+                            assert_eq!(be32(blob, offset), FDT_END);
+                            return node;
+                        }
+                    }
+                }
+                _ => panic!("unexpected token {}", token),
+            }
+        }
+    }
+
+    struct Dev {
+        name: String,
+        compatible: &'static str,
+    }
+
+    impl Device for Dev {
+        fn name(&self) -> String {
+            self.name.clone()
+        }
+        fn read(
+            &mut self,
+            _res_index: usize,
+            _offset: GuestUsize,
+            _data: &mut [u8],
+            _io_type: IoType,
+        ) -> device::Result<()> {
+            Ok(())
+        }
+        fn write(
+            &mut self,
+            _res_index: usize,
+            _offset: GuestUsize,
+            _data: &[u8],
+            _io_type: IoType,
+        ) -> device::Result<()> {
+            Ok(())
+        }
+        fn set_resources(&mut self, _res: &[IoResource], _irq: Option<IrqResource>) {}
+        fn fdt_properties(&self) -> Vec<FdtProperty> {
+            vec![FdtProperty::string("compatible", self.compatible)]
+        }
+    }
+
+    #[test]
+    fn test_fdt_writer() {
+        let mut fdt = FdtWriter::new();
+        assert_eq!(
+            fdt.property(&FdtProperty::empty("ranges")),
+            Err(Error::PropertyOutsideNode("ranges".to_string()))
+        );
+        assert!(fdt.begin_node("cpus").is_err());
+        fdt.begin_node("").unwrap();
+        fdt.property(&FdtProperty::u32("#address-cells", 2))
+            .unwrap();
+        fdt.begin_node("chosen").unwrap();
+        fdt.property(&FdtProperty::string("bootargs", "console=ttyS0"))
+            .unwrap();
+        fdt.property(&FdtProperty::u32("#address-cells", 1))
+            .unwrap();
+        fdt.end_node().unwrap();
+        assert!(fdt.begin_node("a b").is_err());
+        fdt.end_node().unwrap();
+        assert_eq!(fdt.end_node(), Err(Error::UnbalancedNode));
+        let blob = fdt.finish().unwrap();
+
+        let root = parse(&blob);
+        assert_eq!(root.name, "");
+        assert_eq!(root.property("#address-cells"), Some(&[0, 0, 0, 2][..]));
+        let chosen = root.child("chosen");
+        assert_eq!(chosen.property("bootargs"), Some(&b"console=ttyS0\0"[..]));
+        assert_eq!(chosen.property("#address-cells"), Some(&[0, 0, 0, 1][..]));
+        // Property names are stored once.
+        assert_eq!(be32(&blob, 32), "#address-cells\0bootargs\0".len() as u32);
+    }
+
+    #[test]
+    fn test_device_tree() {
+        let mut sys_res =
+            SystemAllocator::new(None, None, GuestAddress(0x1000_0000), 0x1000_0000, 32).unwrap();
+        sys_res.reserve_shared_irqs(&[40]).unwrap();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let bus: Arc<Mutex<dyn Device>> = Arc::new(Mutex::new(Dev {
+            name: "soc".to_string(),
+            compatible: "simple-bus",
+        }));
+        dev_mgr
+            .register_device(bus.clone(), None, &mut Vec::new(), None)
+            .unwrap();
+        let uart = Arc::new(Mutex::new(Dev {
+            name: "uart:0".to_string(),
+            compatible: "ns16550a",
+        }));
+        let mut res_req = vec![IoResource::new(
+            Some(GuestAddress(0x1000_1000)),
+            0x1000,
+            IoType::Mmio,
+        )];
+        dev_mgr
+            .register_device(
+                uart,
+                Some(bus),
+                &mut res_req,
+                Some(IrqResource::SharedLegacy(None)),
+            )
+            .unwrap();
+
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("").unwrap();
+        dev_mgr
+            .write_fdt(&mut fdt, &|irq, level| {
+                vec![0, irq - 32, if level { 4 } else { 1 }]
+            })
+            .unwrap();
+        fdt.end_node().unwrap();
+        let root = parse(&fdt.finish().unwrap());
+
+        let soc = root.child("soc");
+        assert_eq!(soc.property("compatible"), Some(&b"simple-bus\0"[..]));
+        assert_eq!(soc.property("ranges"), Some(&[][..]));
+        assert_eq!(soc.property("#size-cells"), Some(&[0, 0, 0, 2][..]));
+        assert!(soc.property("reg").is_none());
+        let uart = soc.child("uart_0@10001000");
+        assert_eq!(
+            uart.property("reg"),
+            Some(&FdtProperty::cells("reg", &[0, 0x1000_1000, 0, 0x1000]).value[..])
+        );
+        assert_eq!(
+            uart.property("interrupts"),
+            Some(&FdtProperty::cells("interrupts", &[0, 8, 4]).value[..])
+        );
+        assert_eq!(uart.property("compatible"), Some(&b"ns16550a\0"[..]));
+    }
+}
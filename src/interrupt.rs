@@ -0,0 +1,738 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Interrupt delivery.
+//!
+//! Devices get their allocated `IrqResource` through `set_resources()`, and an
+//! [InterruptSourceGroup](trait.InterruptSourceGroup.html) to raise it through
+//! `set_interrupt_group()`. The group keeps the per source state the guest
+//! programs, e.g. the MSI-X table, and delivers the interrupts through the
+//! [InterruptBackend](trait.InterruptBackend.html) provided by the VMM, such
+//! as [IrqfdBackend](struct.IrqfdBackend.html) with KVM.
+
+extern crate vmm_sys_util;
+
+use self::vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+use crate::device::IrqResource;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// MSI message, as programmed by the guest.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct MsiMessage {
+    /// Message address.
+    pub address: u64,
+    /// Message data.
+    pub data: u32,
+}
+
+/// Trait for injecting interrupts into the guest, e.g. through KVM.
+pub trait InterruptBackend: Send + Sync {
+    /// Inject an edge on the interrupt number `irq`.
+    fn trigger(&self, irq: u32) -> io::Result<()>;
+    /// Set the level of the interrupt line `irq`.
+    fn set_level(&self, irq: u32, level: bool) -> io::Result<()>;
+    /// Route the interrupt number `irq` to the MSI message `msg`.
+    fn set_msi_route(&self, irq: u32, msg: MsiMessage) -> io::Result<()>;
+}
+
+/// Trait for the interrupt sources of a device.
+///
+/// Sources are indexed from 0. A source triggered while masked is latched and
+/// delivered once unmasked.
+pub trait InterruptSourceGroup: Send + Sync {
+    /// Return the number of sources.
+    fn len(&self) -> u32;
+    /// Return true if there is no source.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Return the interrupt number of the source `index`.
+    fn irq(&self, index: u32) -> Option<u32>;
+    /// Trigger an edge on the source `index`.
+    fn trigger(&self, index: u32) -> io::Result<()>;
+    /// Assert the level of the source `index`.
+    fn assert(&self, index: u32) -> io::Result<()>;
+    /// Deassert the level of the source `index`.
+    fn deassert(&self, index: u32) -> io::Result<()>;
+    /// Mask the source `index`.
+    fn mask(&self, index: u32) -> io::Result<()>;
+    /// Unmask the source `index`, delivering it if it is pending.
+    fn unmask(&self, index: u32) -> io::Result<()>;
+}
+
+/// Create the interrupt sources of an allocated `irq` resource.
+///
+/// A legacy line gets its own `IrqLine`, use `LegacyIrq::new()` to share one
+/// between devices. Return `None` for not allocated resources.
+pub fn interrupt_group(
+    irq: &IrqResource,
+    backend: Arc<dyn InterruptBackend>,
+) -> Option<Arc<dyn InterruptSourceGroup>> {
+    match *irq {
+        IrqResource::Legacy(Some(irq)) | IrqResource::SharedLegacy(Some(irq)) => {
+            let line = Arc::new(IrqLine::new(irq, backend));
+            Some(Arc::new(LegacyIrq::new(line)))
+        }
+        _ => MsiVectors::new(irq, backend)
+            .map(|vectors| Arc::new(vectors) as Arc<dyn InterruptSourceGroup>),
+    }
+}
+
+fn invalid_source(index: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid interrupt source {}", index),
+    )
+}
+
+/// Legacy interrupt line, possibly shared by several devices.
+///
+/// The level of the line is the OR of the levels of its sources: it gets
+/// asserted with the first source and deasserted with the last one.
+pub struct IrqLine {
+    irq: u32,
+    asserted: Mutex<u32>,
+    backend: Arc<dyn InterruptBackend>,
+}
+
+impl IrqLine {
+    /// Create the interrupt line `irq`.
+    pub fn new(irq: u32, backend: Arc<dyn InterruptBackend>) -> Self {
+        IrqLine {
+            irq,
+            asserted: Mutex::new(0),
+            backend,
+        }
+    }
+
+    /// Return the interrupt number of the line.
+    pub fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    /// Return true if any source asserts the line.
+    pub fn is_asserted(&self) -> bool {
+        *self.lock() > 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, u32> {
+        self.asserted
+            .lock()
+            .expect("Failed to acquire interrupt line lock")
+    }
+
+    fn trigger(&self) -> io::Result<()> {
+        self.backend.trigger(self.irq)
+    }
+
+    fn set_level(&self, level: bool) -> io::Result<()> {
+        let mut asserted = self.lock();
+        if level {
+            *asserted += 1;
+            if *asserted == 1 {
+                return self.backend.set_level(self.irq, true);
+            }
+        } else {
+            *asserted = asserted.saturating_sub(1);
+            if *asserted == 0 {
+                return self.backend.set_level(self.irq, false);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct LineState {
+    masked: bool,
+    level: bool,
+    pending: bool,
+}
+
+/// Source of a device on a legacy interrupt line, the only one of its group.
+///
+/// Masking a source drops its level until it gets unmasked. Dropping it
+/// releases its level on the line.
+pub struct LegacyIrq {
+    line: Arc<IrqLine>,
+    state: Mutex<LineState>,
+}
+
+impl LegacyIrq {
+    /// Create a source on the interrupt line `line`.
+    pub fn new(line: Arc<IrqLine>) -> Self {
+        LegacyIrq {
+            line,
+            state: Mutex::new(LineState::default()),
+        }
+    }
+
+    fn lock(&self, index: u32) -> io::Result<MutexGuard<'_, LineState>> {
+        if index != 0 {
+            return Err(invalid_source(index));
+        }
+        Ok(self
+            .state
+            .lock()
+            .expect("Failed to acquire interrupt source lock"))
+    }
+
+    fn set_level(&self, index: u32, level: bool) -> io::Result<()> {
+        let mut state = self.lock(index)?;
+        if state.level == level {
+            return Ok(());
+        }
+        state.level = level;
+        if state.masked {
+            return Ok(());
+        }
+        self.line.set_level(level)
+    }
+}
+
+impl InterruptSourceGroup for LegacyIrq {
+    fn len(&self) -> u32 {
+        1
+    }
+
+    fn irq(&self, index: u32) -> Option<u32> {
+        if index == 0 {
+            Some(self.line.irq())
+        } else {
+            None
+        }
+    }
+
+    fn trigger(&self, index: u32) -> io::Result<()> {
+        let mut state = self.lock(index)?;
+        if state.masked {
+            state.pending = true;
+            return Ok(());
+        }
+        self.line.trigger()
+    }
+
+    fn assert(&self, index: u32) -> io::Result<()> {
+        self.set_level(index, true)
+    }
+
+    fn deassert(&self, index: u32) -> io::Result<()> {
+        self.set_level(index, false)
+    }
+
+    fn mask(&self, index: u32) -> io::Result<()> {
+        let mut state = self.lock(index)?;
+        if state.masked {
+            return Ok(());
+        }
+        state.masked = true;
+        if state.level {
+            self.line.set_level(false)?;
+        }
+        Ok(())
+    }
+
+    fn unmask(&self, index: u32) -> io::Result<()> {
+        let mut state = self.lock(index)?;
+        if !state.masked {
+            return Ok(());
+        }
+        state.masked = false;
+        if state.level {
+            self.line.set_level(true)?;
+        }
+        if state.pending {
+            state.pending = false;
+            self.line.trigger()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LegacyIrq {
+    fn drop(&mut self) {
+        let _ = self.deassert(0);
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct MsiVector {
+    msg: MsiMessage,
+    masked: bool,
+    pending: bool,
+}
+
+/// MSI or MSI-X vectors of a device.
+///
+/// Vectors are indexed from 0. A vector triggered while masked is latched in
+/// its pending bit and delivered once unmasked.
+pub struct MsiVectors {
+    base: u32,
+    vectors: Mutex<Vec<MsiVector>>,
+    backend: Arc<dyn InterruptBackend>,
+}
+
+impl MsiVectors {
+    /// Create the vectors of an allocated MSI or MSI-X `irq` resource.
+    ///
+    /// Return `None` for legacy or not allocated resources.
+    pub fn new(irq: &IrqResource, backend: Arc<dyn InterruptBackend>) -> Option<Self> {
+        let (base, count) = match *irq {
+            IrqResource::Msi {
+                base: Some(base),
+                count,
+            }
+            | IrqResource::MsiX {
+                base: Some(base),
+                count,
+            } => (base, count),
+            _ => return None,
+        };
+        Some(MsiVectors {
+            base,
+            vectors: Mutex::new(vec![MsiVector::default(); count as usize]),
+            backend,
+        })
+    }
+
+    /// Return the number of vectors.
+    pub fn len(&self) -> u32 {
+        self.lock().len() as u32
+    }
+
+    /// Return true if there is no vector.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the interrupt number of the vector `index`.
+    pub fn irq(&self, index: u32) -> Option<u32> {
+        if index < self.len() {
+            Some(self.base + index)
+        } else {
+            None
+        }
+    }
+
+    /// Set the message of the vector `index`, updating its route.
+    pub fn set_message(&self, index: u32, msg: MsiMessage) -> io::Result<()> {
+        self.with_vector(index, |vector| vector.msg = msg)?;
+        self.backend.set_msi_route(self.base + index, msg)
+    }
+
+    /// Return the message of the vector `index`.
+    pub fn message(&self, index: u32) -> io::Result<MsiMessage> {
+        self.with_vector(index, |vector| vector.msg)
+    }
+
+    /// Mask the vector `index`.
+    pub fn mask(&self, index: u32) -> io::Result<()> {
+        self.with_vector(index, |vector| vector.masked = true)
+    }
+
+    /// Unmask the vector `index`, delivering it if it is pending.
+    pub fn unmask(&self, index: u32) -> io::Result<()> {
+        let pending = self.with_vector(index, |vector| {
+            vector.masked = false;
+            let pending = vector.pending;
+            vector.pending = false;
+            pending
+        })?;
+        if pending {
+            self.backend.trigger(self.base + index)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return true if the vector `index` is masked.
+    pub fn is_masked(&self, index: u32) -> io::Result<bool> {
+        self.with_vector(index, |vector| vector.masked)
+    }
+
+    /// Return true if the vector `index` got triggered while masked.
+    pub fn is_pending(&self, index: u32) -> io::Result<bool> {
+        self.with_vector(index, |vector| vector.pending)
+    }
+
+    /// Trigger the vector `index`.
+    pub fn trigger(&self, index: u32) -> io::Result<()> {
+        let masked = self.with_vector(index, |vector| {
+            vector.pending |= vector.masked;
+            vector.masked
+        })?;
+        if masked {
+            Ok(())
+        } else {
+            self.backend.trigger(self.base + index)
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<MsiVector>> {
+        self.vectors
+            .lock()
+            .expect("Failed to acquire MSI vectors lock")
+    }
+
+    fn with_vector<T, F: FnOnce(&mut MsiVector) -> T>(&self, index: u32, f: F) -> io::Result<T> {
+        let mut vectors = self.lock();
+        vectors
+            .get_mut(index as usize)
+            .map(f)
+            .ok_or_else(|| invalid_source(index))
+    }
+}
+
+/// Message signaled interrupts are edge triggered: asserting a vector sends
+/// its message and deasserting it does nothing.
+impl InterruptSourceGroup for MsiVectors {
+    fn len(&self) -> u32 {
+        MsiVectors::len(self)
+    }
+
+    fn irq(&self, index: u32) -> Option<u32> {
+        MsiVectors::irq(self, index)
+    }
+
+    fn trigger(&self, index: u32) -> io::Result<()> {
+        MsiVectors::trigger(self, index)
+    }
+
+    fn assert(&self, index: u32) -> io::Result<()> {
+        MsiVectors::trigger(self, index)
+    }
+
+    fn deassert(&self, index: u32) -> io::Result<()> {
+        self.with_vector(index, |_| ())
+    }
+
+    fn mask(&self, index: u32) -> io::Result<()> {
+        MsiVectors::mask(self, index)
+    }
+
+    fn unmask(&self, index: u32) -> io::Result<()> {
+        MsiVectors::unmask(self, index)
+    }
+}
+
+/// Callback installing the whole MSI routing table, e.g. with `KVM_SET_GSI_ROUTING`.
+pub type MsiRoutingHandler = dyn Fn(&[(u32, MsiMessage)]) -> io::Result<()> + Send + Sync;
+
+/// Backend signaling one eventfd per interrupt number, for KVM irqfds.
+///
+/// The VMM registers the eventfd returned by `irqfd()` for each allocated
+/// interrupt number with `KVM_IRQFD`. Level triggered lines are registered
+/// with `KVM_IRQFD_FLAG_RESAMPLE` and the eventfd returned by `resamplefd()`:
+/// asserting a line signals its irqfd, KVM deasserts it on EOI and signals
+/// the resample eventfd, upon which the VMM calls `resample()` to inject the
+/// line again while a source still asserts it.
+pub struct IrqfdBackend {
+    irqfds: Mutex<BTreeMap<u32, Arc<EventFd>>>,
+    resamplefds: Mutex<BTreeMap<u32, Arc<EventFd>>>,
+    asserted: Mutex<BTreeSet<u32>>,
+    routes: Mutex<BTreeMap<u32, MsiMessage>>,
+    routing: Box<MsiRoutingHandler>,
+}
+
+impl IrqfdBackend {
+    /// Create a backend calling `routing` every time the MSI routes change.
+    pub fn new(routing: Box<MsiRoutingHandler>) -> Self {
+        IrqfdBackend {
+            irqfds: Mutex::new(BTreeMap::new()),
+            resamplefds: Mutex::new(BTreeMap::new()),
+            asserted: Mutex::new(BTreeSet::new()),
+            routes: Mutex::new(BTreeMap::new()),
+            routing,
+        }
+    }
+
+    /// Return the eventfd of the interrupt number `irq`, creating it if needed.
+    pub fn irqfd(&self, irq: u32) -> io::Result<Arc<EventFd>> {
+        let mut irqfds = self.irqfds.lock().expect("Failed to acquire irqfd lock");
+        if let Some(irqfd) = irqfds.get(&irq) {
+            return Ok(irqfd.clone());
+        }
+        let irqfd = Arc::new(EventFd::new(EFD_NONBLOCK)?);
+        irqfds.insert(irq, irqfd.clone());
+        Ok(irqfd)
+    }
+
+    /// Return the resample eventfd of the level triggered line `irq`,
+    /// creating it if needed.
+    pub fn resamplefd(&self, irq: u32) -> io::Result<Arc<EventFd>> {
+        let mut resamplefds = self
+            .resamplefds
+            .lock()
+            .expect("Failed to acquire resamplefd lock");
+        if let Some(resamplefd) = resamplefds.get(&irq) {
+            return Ok(resamplefd.clone());
+        }
+        let resamplefd = Arc::new(EventFd::new(EFD_NONBLOCK)?);
+        resamplefds.insert(irq, resamplefd.clone());
+        Ok(resamplefd)
+    }
+
+    /// Handle the EOI of the level triggered line `irq`, signaled through its
+    /// resample eventfd: consume the signal and inject the line again if a
+    /// source still asserts it.
+    pub fn resample(&self, irq: u32) -> io::Result<()> {
+        if let Err(e) = self.resamplefd(irq)?.read() {
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e);
+            }
+        }
+        let asserted = self.lock_asserted();
+        if asserted.contains(&irq) {
+            self.trigger(irq)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn lock_asserted(&self) -> MutexGuard<'_, BTreeSet<u32>> {
+        self.asserted
+            .lock()
+            .expect("Failed to acquire interrupt level lock")
+    }
+}
+
+impl InterruptBackend for IrqfdBackend {
+    fn trigger(&self, irq: u32) -> io::Result<()> {
+        self.irqfd(irq)?.write(1)
+    }
+
+    fn set_level(&self, irq: u32, level: bool) -> io::Result<()> {
+        let mut asserted = self.lock_asserted();
+        if level {
+            asserted.insert(irq);
+            self.trigger(irq)
+        } else {
+            // KVM keeps the line asserted until the guest EOI.
+            asserted.remove(&irq);
+            Ok(())
+        }
+    }
+
+    fn set_msi_route(&self, irq: u32, msg: MsiMessage) -> io::Result<()> {
+        let mut routes = self.routes.lock().expect("Failed to acquire route lock");
+        if routes.insert(irq, msg) == Some(msg) {
+            return Ok(());
+        }
+        let table: Vec<(u32, MsiMessage)> = routes.iter().map(|(irq, msg)| (*irq, *msg)).collect();
+        (self.routing)(&table)
+    }
+}
+
+/// Interrupt injected through an `InterruptRecorder`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum InterruptEvent {
+    /// Edge on an interrupt number.
+    Edge(u32),
+    /// Level change of an interrupt line.
+    Level(u32, bool),
+    /// Route change of an interrupt number.
+    MsiRoute(u32, MsiMessage),
+}
+
+/// Backend recording the injected interrupts, for testing devices.
+#[derive(Default)]
+pub struct InterruptRecorder {
+    events: Mutex<Vec<InterruptEvent>>,
+}
+
+impl InterruptRecorder {
+    /// Return and forget the recorded interrupts.
+    pub fn take_events(&self) -> Vec<InterruptEvent> {
+        self.lock().drain(..).collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<InterruptEvent>> {
+        self.events.lock().expect("Failed to acquire recorder lock")
+    }
+}
+
+impl InterruptBackend for InterruptRecorder {
+    fn trigger(&self, irq: u32) -> io::Result<()> {
+        self.lock().push(InterruptEvent::Edge(irq));
+        Ok(())
+    }
+
+    fn set_level(&self, irq: u32, level: bool) -> io::Result<()> {
+        self.lock().push(InterruptEvent::Level(irq, level));
+        Ok(())
+    }
+
+    fn set_msi_route(&self, irq: u32, msg: MsiMessage) -> io::Result<()> {
+        self.lock().push(InterruptEvent::MsiRoute(irq, msg));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InterruptEvent::*;
+    use super::*;
+
+    #[test]
+    fn test_msi_vectors() {
+        let recorder = Arc::new(InterruptRecorder::default());
+        let irq = IrqResource::MsiX {
+            base: Some(24),
+            count: 2,
+        };
+        let vectors = MsiVectors::new(&irq, recorder.clone()).unwrap();
+        assert_eq!(vectors.len(), 2);
+        assert_eq!(vectors.irq(1), Some(25));
+        assert_eq!(vectors.irq(2), None);
+
+        let msg = MsiMessage {
+            address: 0xfee0_0000,
+            data: 0x41,
+        };
+        vectors.set_message(1, msg).unwrap();
+        vectors.trigger(1).unwrap();
+        assert_eq!(recorder.take_events(), vec![MsiRoute(25, msg), Edge(25)]);
+
+        // Masked vectors are latched until unmasked.
+        vectors.mask(1).unwrap();
+        vectors.trigger(1).unwrap();
+        assert!(vectors.is_pending(1).unwrap());
+        assert!(recorder.take_events().is_empty());
+        vectors.unmask(1).unwrap();
+        assert!(!vectors.is_pending(1).unwrap());
+        assert_eq!(recorder.take_events(), vec![Edge(25)]);
+
+        assert!(vectors.trigger(2).is_err());
+    }
+
+    #[test]
+    fn test_msi_vectors_legacy() {
+        let recorder = Arc::new(InterruptRecorder::default());
+        assert!(MsiVectors::new(&IrqResource::Legacy(Some(5)), recorder.clone()).is_none());
+        assert!(MsiVectors::new(
+            &IrqResource::Msi {
+                base: None,
+                count: 4
+            },
+            recorder
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_legacy_irq() {
+        let recorder = Arc::new(InterruptRecorder::default());
+        let group = interrupt_group(&IrqResource::Legacy(Some(4)), recorder.clone()).unwrap();
+        assert_eq!(group.len(), 1);
+        assert_eq!(group.irq(0), Some(4));
+
+        group.trigger(0).unwrap();
+        group.assert(0).unwrap();
+        group.deassert(0).unwrap();
+        assert_eq!(
+            recorder.take_events(),
+            vec![Edge(4), Level(4, true), Level(4, false)]
+        );
+
+        // Masking drops the level, which comes back once unmasked.
+        group.assert(0).unwrap();
+        group.mask(0).unwrap();
+        group.trigger(0).unwrap();
+        group.unmask(0).unwrap();
+        assert_eq!(
+            recorder.take_events(),
+            vec![Level(4, true), Level(4, false), Level(4, true), Edge(4)]
+        );
+
+        assert!(group.trigger(1).is_err());
+        assert!(interrupt_group(&IrqResource::Legacy(None), recorder).is_none());
+    }
+
+    #[test]
+    fn test_irqfd_backend() {
+        let table = Arc::new(Mutex::new(Vec::new()));
+        let routes = table.clone();
+        let backend = IrqfdBackend::new(Box::new(move |t: &[(u32, MsiMessage)]| {
+            *routes.lock().unwrap() = t.to_vec();
+            Ok(())
+        }));
+
+        let irqfd = backend.irqfd(5).unwrap();
+        backend.trigger(5).unwrap();
+        backend.set_level(5, true).unwrap();
+        backend.set_level(5, false).unwrap();
+        assert_eq!(irqfd.read().unwrap(), 2);
+
+        let msg = MsiMessage {
+            address: 0xfee0_0000,
+            data: 0x30,
+        };
+        backend.set_msi_route(7, msg).unwrap();
+        backend.set_msi_route(6, msg).unwrap();
+        assert_eq!(*table.lock().unwrap(), vec![(6, msg), (7, msg)]);
+    }
+
+    #[test]
+    fn test_irqfd_resample() {
+        let backend = Arc::new(IrqfdBackend::new(Box::new(
+            |_: &[(u32, MsiMessage)]| Ok(()),
+        )));
+        let irqfd = backend.irqfd(10).unwrap();
+        let resamplefd = backend.resamplefd(10).unwrap();
+        let line = Arc::new(IrqLine::new(10, backend.clone()));
+        let a = LegacyIrq::new(line.clone());
+        let b = LegacyIrq::new(line);
+        // Emulate KVM deasserting the line on EOI and signaling the resamplefd.
+        let eoi = || {
+            resamplefd.write(1).unwrap();
+            backend.resample(10).unwrap();
+        };
+
+        a.assert(0).unwrap();
+        b.assert(0).unwrap();
+        assert_eq!(irqfd.read().unwrap(), 1);
+
+        // The line gets injected again as long as a source asserts it.
+        eoi();
+        assert_eq!(irqfd.read().unwrap(), 1);
+        a.deassert(0).unwrap();
+        eoi();
+        assert_eq!(irqfd.read().unwrap(), 1);
+        b.deassert(0).unwrap();
+        eoi();
+        assert!(irqfd.read().is_err());
+        assert!(resamplefd.read().is_err());
+    }
+
+    #[test]
+    fn test_shared_irq_line() {
+        let recorder = Arc::new(InterruptRecorder::default());
+        let line = Arc::new(IrqLine::new(10, recorder.clone()));
+        let a = LegacyIrq::new(line.clone());
+        let b = LegacyIrq::new(line.clone());
+
+        // The line level is the OR of the source levels.
+        a.assert(0).unwrap();
+        b.assert(0).unwrap();
+        a.assert(0).unwrap();
+        a.deassert(0).unwrap();
+        assert!(line.is_asserted());
+        assert_eq!(recorder.take_events(), vec![Level(10, true)]);
+        b.mask(0).unwrap();
+        assert!(!line.is_asserted());
+        b.unmask(0).unwrap();
+        assert_eq!(
+            recorder.take_events(),
+            vec![Level(10, false), Level(10, true)]
+        );
+
+        // A source going away releases the line.
+        drop(b);
+        assert!(!line.is_asserted());
+        assert_eq!(recorder.take_events(), vec![Level(10, false)]);
+        drop(a);
+        assert!(recorder.take_events().is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Write notifications through eventfds.
+//!
+//! Devices declare the registers whose writes only need to wake up a worker,
+//! such as virtio queue notify registers, as [Doorbell](struct.Doorbell.html)s.
+//! The `DeviceManager` lists them with their guest address as
+//! [IoEvent](struct.IoEvent.html)s, for the VMM to register with `KVM_IOEVENTFD`
+//! so that the matching writes never exit. Writes that still exit, e.g.
+//! because the VMM lacks ioeventfd support, signal the eventfd on the VM exit
+//! path without reaching the device `write()` handler.
+
+extern crate vmm_sys_util;
+
+use self::vmm_sys_util::eventfd::EventFd;
+use crate::device::IoType;
+use std::io;
+use std::sync::Arc;
+use vm_memory::{GuestAddress, GuestUsize};
+
+/// Register of a device whose writes signal an eventfd.
+#[derive(Clone)]
+pub struct Doorbell {
+    /// Index of the resource holding the register, in the set handed over by
+    /// `set_resources()`.
+    pub res_index: usize,
+    /// Offset of the register within the resource.
+    pub offset: GuestUsize,
+    /// Width of the matched writes, 0 for any width.
+    pub len: usize,
+    /// Value of the matched writes, any value if `None`. Requires a width.
+    pub datamatch: Option<u64>,
+    /// Event signaled by the matched writes.
+    pub event: Arc<EventFd>,
+}
+
+impl Doorbell {
+    /// Build a Doorbell struct.
+    pub fn new(
+        res_index: usize,
+        offset: GuestUsize,
+        len: usize,
+        datamatch: Option<u64>,
+        event: Arc<EventFd>,
+    ) -> Self {
+        Doorbell {
+            res_index,
+            offset,
+            len,
+            datamatch,
+            event,
+        }
+    }
+
+    /// Return true if writing `data` at `offset` rings the doorbell.
+    pub fn matches(&self, offset: GuestUsize, data: &[u8]) -> bool {
+        if offset != self.offset || (self.len != 0 && data.len() != self.len) {
+            return false;
+        }
+        match self.datamatch {
+            Some(value) => {
+                self.len != 0
+                    && data.len() <= 8
+                    && data
+                        .iter()
+                        .enumerate()
+                        .fold(0u64, |v, (i, b)| v | u64::from(*b) << (i * 8))
+                        == value
+            }
+            None => true,
+        }
+    }
+
+    /// Signal the doorbell event.
+    pub fn ring(&self) -> io::Result<()> {
+        self.event.write(1)
+    }
+}
+
+/// Eventfd registration of a doorbell, at its guest address.
+#[derive(Clone)]
+pub struct IoEvent {
+    /// Guest address of the register.
+    pub addr: GuestAddress,
+    /// Address space of the register.
+    pub io_type: IoType,
+    /// Width of the matched writes, 0 for any width.
+    pub len: usize,
+    /// Value of the matched writes, any value if `None`.
+    pub datamatch: Option<u64>,
+    /// Event to signal on the matched writes.
+    pub event: Arc<EventFd>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::vmm_sys_util::eventfd::EFD_NONBLOCK;
+    use super::*;
+
+    #[test]
+    fn test_doorbell_match() {
+        let event = Arc::new(EventFd::new(EFD_NONBLOCK).unwrap());
+        let any = Doorbell::new(0, 0x50, 0, None, event.clone());
+        assert!(any.matches(0x50, &[1]));
+        assert!(any.matches(0x50, &[1, 0, 0, 0]));
+        assert!(!any.matches(0x54, &[1]));
+
+        let queue = Doorbell::new(0, 0x50, 4, Some(2), event.clone());
+        assert!(queue.matches(0x50, &[2, 0, 0, 0]));
+        assert!(!queue.matches(0x50, &[1, 0, 0, 0]));
+        assert!(!queue.matches(0x50, &[2, 0]));
+
+        queue.ring().unwrap();
+        queue.ring().unwrap();
+        assert_eq!(event.read().unwrap(), 2);
+    }
+}
@@ -12,8 +12,30 @@
 
 extern crate vm_memory;
 
+pub mod acpi;
+pub mod bus;
 pub mod device;
 pub mod device_manager;
+pub mod fdt;
+pub mod interrupt;
+pub mod ioevent;
+pub mod memory;
+pub mod pci;
+pub mod snapshot;
+pub mod virtio_mmio;
 
-pub use self::device::{Device, DeviceDescriptor, IoResource, IoType};
+pub use self::bus::{
+    Fallback, IoDispatcher, SpanPolicy, UnclaimedAccess, MAX_UNCLAIMED_ADDRESSES, MAX_UNCLAIMED_LOG,
+};
+pub use self::device::{
+    Device, DeviceDescriptor, DeviceHandle, Error as DeviceError, IoResource, IoType, IrqResource,
+    SharedDevice,
+};
 pub use self::device_manager::{DeviceManager, Error as DeviceManagerError, Range, Result};
+pub use self::interrupt::{
+    InterruptBackend, InterruptRecorder, InterruptSourceGroup, IrqLine, IrqfdBackend, LegacyIrq,
+    MsiMessage, MsiVectors,
+};
+pub use self::ioevent::{Doorbell, IoEvent};
+pub use self::memory::{MemoryBacking, MemoryMapper};
+pub use self::snapshot::{DeviceManagerState, DeviceState, Snapshot};
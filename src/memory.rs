@@ -0,0 +1,53 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Guest mapping of device memory.
+//!
+//! `IoType::PhysicalMmio` resources do not trap: the guest accesses host
+//! memory provided by the device, such as a VFIO BAR or a shared memory
+//! region. Devices describe it as a [MemoryBacking](enum.MemoryBacking.html)
+//! and the `DeviceManager` maps it into the guest through the
+//! [MemoryMapper](trait.MemoryMapper.html) provided by the VMM, e.g. with
+//! `KVM_SET_USER_MEMORY_REGION`.
+
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+use vm_memory::{GuestAddress, GuestUsize};
+
+/// Host memory backing a `PhysicalMmio` resource.
+#[derive(Clone, Debug)]
+pub enum MemoryBacking {
+    /// Range of a file to map, starting at `offset`.
+    File {
+        /// Mapped file, e.g. a VFIO device or a shared memory file.
+        file: Arc<File>,
+        /// Offset of the range in the file.
+        offset: u64,
+    },
+    /// Host memory already mapped by the device, e.g. a vm-memory
+    /// `MmapRegion`, starting at the host virtual address `addr`.
+    Host {
+        /// Host virtual address of the memory.
+        addr: usize,
+    },
+}
+
+/// Trait for mapping device memory into the guest physical address space.
+pub trait MemoryMapper: Send + Sync {
+    /// Map `size` bytes of `backing` at the guest address `addr`.
+    fn map(&self, addr: GuestAddress, size: GuestUsize, backing: &MemoryBacking) -> io::Result<()>;
+    /// Unmap the `size` bytes mapped at the guest address `addr`.
+    fn unmap(&self, addr: GuestAddress, size: GuestUsize) -> io::Result<()>;
+    /// Move the `size` bytes of `backing` mapped at `old` to `new`.
+    fn remap(
+        &self,
+        old: GuestAddress,
+        new: GuestAddress,
+        size: GuestUsize,
+        backing: &MemoryBacking,
+    ) -> io::Result<()> {
+        self.unmap(old, size)?;
+        self.map(new, size, backing)
+    }
+}
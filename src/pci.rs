@@ -0,0 +1,887 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! PCI subsystem.
+//!
+//! A PCI function implements [PciDevice](trait.PciDevice.html) and gets
+//! wrapped into a [PciFunction](struct.PciFunction.html), the `Device`
+//! registered into the `DeviceManager`. Its configuration space is mapped in
+//! the `IoType::PciConfig` address space and each of its BARs turns into an
+//! `IoResource` request.
+//!
+//! The [PciRoot](struct.PciRoot.html) device is the root bus: it hands out
+//! the function addresses, emulates the host bridge and forwards the guest
+//! configuration accesses, through the CF8/CFC PIO mechanism or its ECAM MMIO
+//! window, to the `IoType::PciConfig` address space.
+
+use crate::acpi::{AcpiDevice, AcpiTableEntry};
+use crate::bus::IoDispatcher;
+use crate::device;
+use crate::device::{Device, IoResource, IoType, IrqResource};
+use crate::interrupt::InterruptSourceGroup;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Arc;
+use vm_memory::{GuestAddress, GuestUsize};
+
+/// Size of the configuration space of a function.
+pub const PCI_CONFIG_SIZE: GuestUsize = 0x1000;
+/// Size of the ECAM window of a bus.
+pub const PCI_ECAM_BUS_SIZE: GuestUsize = 32 * 8 * PCI_CONFIG_SIZE;
+/// Base of the CF8/CFC configuration mechanism ports.
+pub const PCI_CONFIG_IO_PORT: u64 = 0xcf8;
+
+/// I/O space decode bit of the command register.
+pub const COMMAND_IO: u16 = 0x1;
+/// Memory space decode bit of the command register.
+pub const COMMAND_MEMORY: u16 = 0x2;
+
+const PCI_MAX_DEVICES: u8 = 32;
+const PCI_MAX_FUNCTIONS: u8 = 8;
+const COMMAND_REG: usize = 1;
+const BAR0_REG: usize = 4;
+const NUM_BARS: usize = 6;
+const INTERRUPT_REG: usize = 15;
+
+/// Address of a PCI function.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PciAddress {
+    /// Bus number.
+    pub bus: u8,
+    /// Device number, up to 31.
+    pub device: u8,
+    /// Function number, up to 7.
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// Build a PciAddress struct.
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        PciAddress {
+            bus,
+            device: device & (PCI_MAX_DEVICES - 1),
+            function: function & (PCI_MAX_FUNCTIONS - 1),
+        }
+    }
+
+    /// Build the address from its bus/device/function number.
+    pub fn from_bdf(bdf: u16) -> Self {
+        PciAddress::new((bdf >> 8) as u8, (bdf >> 3) as u8, bdf as u8)
+    }
+
+    /// Return the bus/device/function number.
+    pub fn bdf(&self) -> u16 {
+        u16::from(self.bus) << 8 | u16::from(self.device) << 3 | u16::from(self.function)
+    }
+
+    /// Return the `IoType::PciConfig` address of the register `reg`.
+    pub fn config_address(&self, reg: u64) -> GuestAddress {
+        GuestAddress(u64::from(self.bdf()) << 12 | (reg & (PCI_CONFIG_SIZE - 1)))
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// Type of a BAR.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PciBarType {
+    /// I/O space BAR. The SystemAllocator does not allocate PIO addresses,
+    /// these BARs need a fixed address.
+    Io,
+    /// 32 bit memory space BAR.
+    Mmio32,
+    /// 64 bit memory space BAR, using two BAR registers.
+    Mmio64,
+}
+
+/// Base address register of a function.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PciBar {
+    /// Index of the first BAR register.
+    pub index: usize,
+    /// Size of the region, a power of two.
+    pub size: GuestUsize,
+    /// Region type.
+    pub bar_type: PciBarType,
+    /// Fixed address of the region, if any.
+    pub addr: Option<GuestAddress>,
+}
+
+impl PciBar {
+    /// Build a PciBar struct.
+    pub fn new(index: usize, size: GuestUsize, bar_type: PciBarType) -> Self {
+        PciBar {
+            index,
+            size,
+            bar_type,
+            addr: None,
+        }
+    }
+
+    fn flags(&self) -> u32 {
+        match self.bar_type {
+            PciBarType::Io => 0x1,
+            PciBarType::Mmio32 => 0x0,
+            PciBarType::Mmio64 => 0x4,
+        }
+    }
+
+    // Guests size a BAR by writing all ones to its registers.
+    fn is_sizing(&self, addr: GuestAddress) -> bool {
+        let ones = addr.0 | (self.size - 1) | 0xf;
+        ones as u32 == u32::MAX
+            || (self.bar_type == PciBarType::Mmio64 && (ones >> 32) as u32 == u32::MAX)
+    }
+
+    fn decode_bit(&self) -> u16 {
+        match self.bar_type {
+            PciBarType::Io => COMMAND_IO,
+            PciBarType::Mmio32 | PciBarType::Mmio64 => COMMAND_MEMORY,
+        }
+    }
+
+    fn io_type(&self) -> IoType {
+        match self.bar_type {
+            PciBarType::Io => IoType::Pio,
+            PciBarType::Mmio32 | PciBarType::Mmio64 => IoType::Mmio,
+        }
+    }
+}
+
+/// Type 0 configuration space of a function.
+///
+/// Writes only change the writable bits of the registers. Writing all ones
+/// to a BAR and reading it back returns its size, as expected by guests
+/// sizing the BARs.
+pub struct PciConfiguration {
+    regs: Vec<u32>,
+    writable: Vec<u32>,
+    bars: Vec<PciBar>,
+}
+
+impl PciConfiguration {
+    /// Create the configuration space of a function.
+    pub fn new(vendor_id: u16, device_id: u16, class: u8, subclass: u8, prog_if: u8) -> Self {
+        let size = PCI_CONFIG_SIZE as usize / 4;
+        let mut config = PciConfiguration {
+            regs: vec![0; size],
+            writable: vec![0; size],
+            bars: Vec::new(),
+        };
+        config.regs[0] = u32::from(device_id) << 16 | u32::from(vendor_id);
+        config.regs[2] =
+            u32::from(class) << 24 | u32::from(subclass) << 16 | u32::from(prog_if) << 8;
+        // Command register, and interrupt line.
+        config.writable[COMMAND_REG] = 0x0000_07ff;
+        config.writable[INTERRUPT_REG] = 0x0000_00ff;
+        config
+    }
+
+    /// Add a BAR, returning `None` if its registers are already used or it
+    /// has an invalid size.
+    pub fn add_bar(&mut self, bar: PciBar) -> Option<()> {
+        let regs = if bar.bar_type == PciBarType::Mmio64 {
+            2
+        } else {
+            1
+        };
+        let min_size = if bar.bar_type == PciBarType::Io {
+            4
+        } else {
+            16
+        };
+        if bar.index + regs > NUM_BARS
+            || !bar.size.is_power_of_two()
+            || bar.size < min_size
+            || (bar.bar_type != PciBarType::Mmio64 && bar.size > 1 << 31)
+            || self
+                .bars
+                .iter()
+                .any(|b| b.index == bar.index || (bar.index + regs - 1 == b.index))
+            || self
+                .bars
+                .iter()
+                .any(|b| b.bar_type == PciBarType::Mmio64 && b.index + 1 == bar.index)
+        {
+            return None;
+        }
+        let mask = !(bar.size - 1);
+        let reg = BAR0_REG + bar.index;
+        self.regs[reg] = bar.flags();
+        self.writable[reg] = match bar.bar_type {
+            PciBarType::Io => mask as u32 & !0x3,
+            PciBarType::Mmio32 | PciBarType::Mmio64 => mask as u32 & !0xf,
+        };
+        if bar.bar_type == PciBarType::Mmio64 {
+            self.writable[reg + 1] = (mask >> 32) as u32;
+        }
+        self.bars.push(bar);
+        if let Some(addr) = bar.addr {
+            self.set_bar_address(bar.index, addr);
+        }
+        Some(())
+    }
+
+    /// Return the BARs, by increasing index.
+    pub fn bars(&self) -> Vec<PciBar> {
+        let mut bars = self.bars.clone();
+        bars.sort_by_key(|bar| bar.index);
+        bars
+    }
+
+    /// Return the address programmed in the BAR `index`.
+    pub fn bar_address(&self, index: usize) -> Option<GuestAddress> {
+        let bar = self.bars.iter().find(|bar| bar.index == index)?;
+        let reg = BAR0_REG + index;
+        let mut addr = u64::from(self.regs[reg] & self.writable[reg]);
+        if bar.bar_type == PciBarType::Mmio64 {
+            addr |= u64::from(self.regs[reg + 1]) << 32;
+        }
+        Some(GuestAddress(addr))
+    }
+
+    /// Program the address of the BAR `index`.
+    pub fn set_bar_address(&mut self, index: usize, addr: GuestAddress) {
+        if let Some(bar) = self.bars.iter().find(|bar| bar.index == index).cloned() {
+            let reg = BAR0_REG + index;
+            self.regs[reg] = (addr.0 as u32 & self.writable[reg]) | bar.flags();
+            if bar.bar_type == PciBarType::Mmio64 {
+                self.regs[reg + 1] = (addr.0 >> 32) as u32;
+            }
+        }
+    }
+
+    /// Return the command register.
+    pub fn command(&self) -> u16 {
+        self.regs[COMMAND_REG] as u16
+    }
+
+    /// Set the interrupt line and pin, 1 for INTA#.
+    pub fn set_interrupt(&mut self, line: u8, pin: u8) {
+        self.regs[INTERRUPT_REG] =
+            self.regs[INTERRUPT_REG] & !0xffff | u32::from(pin) << 8 | u32::from(line);
+    }
+
+    /// Read the registers at `offset`.
+    pub fn read(&self, offset: u64, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            let offset = offset as usize + i;
+            *byte = match self.regs.get(offset / 4) {
+                Some(reg) => (reg >> ((offset % 4) * 8)) as u8,
+                None => 0xff,
+            };
+        }
+    }
+
+    /// Write the registers at `offset`.
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            let offset = offset as usize + i;
+            if let Some(reg) = self.regs.get_mut(offset / 4) {
+                let shift = (offset % 4) * 8;
+                let mask = self.writable[offset / 4] & (0xff << shift);
+                *reg = *reg & !mask | (u32::from(*byte) << shift) & mask;
+            }
+        }
+    }
+}
+
+/// Trait for PCI functions.
+#[allow(unused_variables)]
+pub trait PciDevice: Send {
+    /// Get the device name.
+    fn name(&self) -> String;
+    /// Return the configuration space of the function.
+    fn config(&self) -> &PciConfiguration;
+    /// Return the configuration space of the function for modification.
+    fn config_mut(&mut self) -> &mut PciConfiguration;
+    /// Read the configuration space at `offset`.
+    fn config_read(&mut self, offset: u64, data: &mut [u8]) {
+        self.config().read(offset, data)
+    }
+    /// Write the configuration space at `offset`.
+    fn config_write(&mut self, offset: u64, data: &[u8]) {
+        self.config_mut().write(offset, data)
+    }
+    /// Read from the region of the BAR `bar` at `offset` to `data`.
+    fn bar_read(&mut self, bar: usize, offset: GuestUsize, data: &mut [u8]) -> device::Result<()>;
+    /// Write `data` to the region of the BAR `bar` at `offset`.
+    fn bar_write(&mut self, bar: usize, offset: GuestUsize, data: &[u8]) -> device::Result<()>;
+    /// Set the interrupt sources of the function.
+    fn set_interrupt_group(&mut self, group: Arc<dyn InterruptSourceGroup>) {}
+}
+
+/// `Device` exposing a PCI function at its address.
+///
+/// The resources of the function are its configuration space, followed by
+/// one resource per BAR by increasing BAR index. The BARs the guest moves are
+/// reported through `take_moved_resources()`, see
+/// `DeviceManager::relocate_moved_resources()`, once the decoding of their
+/// address space is enabled in the command register.
+pub struct PciFunction {
+    address: PciAddress,
+    device: Box<dyn PciDevice>,
+    bars: Vec<PciBar>,
+    resources: Vec<IoResource>,
+    moved: Vec<(usize, GuestAddress)>,
+}
+
+impl PciFunction {
+    /// Create the function `device` at `address`.
+    pub fn new(address: PciAddress, device: Box<dyn PciDevice>) -> Self {
+        let bars = device.config().bars();
+        PciFunction {
+            address,
+            device,
+            bars,
+            resources: Vec::new(),
+            moved: Vec::new(),
+        }
+    }
+
+    /// Return the address of the function.
+    pub fn address(&self) -> PciAddress {
+        self.address
+    }
+
+    /// Return the resource requests of the function, to be registered.
+    pub fn resources(&self) -> Vec<IoResource> {
+        let mut resources = vec![IoResource::new(
+            Some(self.address.config_address(0)),
+            PCI_CONFIG_SIZE,
+            IoType::PciConfig,
+        )];
+        resources.extend(
+            self.bars
+                .iter()
+                .map(|bar| IoResource::new(bar.addr, bar.size, bar.io_type())),
+        );
+        resources
+    }
+
+    // BARs only move while the guest lets the function decode their address
+    // space, it may program them in several steps with decoding disabled.
+    fn check_moved_bars(&mut self) {
+        let command = self.device.config().command();
+        for (i, bar) in self.bars.iter().enumerate() {
+            if command & bar.decode_bit() == 0 {
+                continue;
+            }
+            let current = match self.resources.get(i + 1).and_then(|res| res.addr) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let addr = match self.device.config().bar_address(bar.index) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            if addr == current || bar.is_sizing(addr) {
+                continue;
+            }
+            self.moved.retain(|(idx, _)| *idx != i + 1);
+            self.moved.push((i + 1, addr));
+        }
+    }
+}
+
+impl Device for PciFunction {
+    fn name(&self) -> String {
+        self.device.name()
+    }
+
+    fn read(
+        &mut self,
+        res_index: usize,
+        offset: GuestUsize,
+        data: &mut [u8],
+        _io_type: IoType,
+    ) -> device::Result<()> {
+        match res_index {
+            0 => {
+                self.device.config_read(offset, data);
+                Ok(())
+            }
+            _ => {
+                let bar = self
+                    .bars
+                    .get(res_index - 1)
+                    .ok_or(device::Error::InvalidResource(res_index))?;
+                self.device.bar_read(bar.index, offset, data)
+            }
+        }
+    }
+
+    fn write(
+        &mut self,
+        res_index: usize,
+        offset: GuestUsize,
+        data: &[u8],
+        _io_type: IoType,
+    ) -> device::Result<()> {
+        match res_index {
+            0 => {
+                self.device.config_write(offset, data);
+                self.check_moved_bars();
+                Ok(())
+            }
+            _ => {
+                let bar = self
+                    .bars
+                    .get(res_index - 1)
+                    .ok_or(device::Error::InvalidResource(res_index))?;
+                self.device.bar_write(bar.index, offset, data)
+            }
+        }
+    }
+
+    fn set_resources(&mut self, res: &[IoResource], irq: Option<IrqResource>) {
+        for (bar, res) in self.bars.iter().zip(res.iter().skip(1)) {
+            if let Some(addr) = res.addr {
+                self.device.config_mut().set_bar_address(bar.index, addr);
+            }
+        }
+        if let Some(IrqResource::Legacy(Some(line))) | Some(IrqResource::SharedLegacy(Some(line))) =
+            irq
+        {
+            // The Interrupt Line register only holds lines up to 254, 0xff
+            // meaning unknown or not connected.
+            let line = u8::try_from(line).unwrap_or(0xff);
+            self.device.config_mut().set_interrupt(line, 1);
+        }
+        self.resources = res.to_vec();
+        self.moved.clear();
+    }
+
+    fn take_moved_resources(&mut self) -> Vec<(usize, GuestAddress)> {
+        self.moved.drain(..).collect()
+    }
+
+    fn set_interrupt_group(&mut self, group: Arc<dyn InterruptSourceGroup>) {
+        self.device.set_interrupt_group(group)
+    }
+}
+
+/// Root bus, with the host bridge at `00:00.0`.
+///
+/// Its resources are the CF8/CFC ports followed by the ECAM window of bus 0.
+pub struct PciRoot {
+    name: String,
+    io: IoDispatcher,
+    config_address: u32,
+    host_bridge: PciConfiguration,
+    addresses: BTreeSet<PciAddress>,
+    ecam: Option<GuestAddress>,
+}
+
+impl PciRoot {
+    /// Create the root bus `name`, forwarding the configuration accesses
+    /// through `io`, e.g. `DeviceManager::io_dispatcher()`.
+    pub fn new(name: &str, io: IoDispatcher) -> Self {
+        let mut addresses = BTreeSet::new();
+        addresses.insert(PciAddress::new(0, 0, 0));
+        PciRoot {
+            name: name.to_string(),
+            io,
+            config_address: 0,
+            // Host bridge.
+            host_bridge: PciConfiguration::new(0x8086, 0x0d57, 0x06, 0x00, 0x00),
+            addresses,
+            ecam: None,
+        }
+    }
+
+    /// Return the resource requests of the root bus, to be registered.
+    pub fn resources(&self) -> Vec<IoResource> {
+        vec![
+            IoResource::new(Some(GuestAddress(PCI_CONFIG_IO_PORT)), 8, IoType::Pio),
+            IoResource::new(None, PCI_ECAM_BUS_SIZE, IoType::Mmio),
+        ]
+    }
+
+    /// Return the base of the ECAM window, once allocated.
+    pub fn ecam_base(&self) -> Option<GuestAddress> {
+        self.ecam
+    }
+
+    /// Allocate the function 0 of the first free device number of bus 0.
+    pub fn allocate_address(&mut self) -> Option<PciAddress> {
+        let address = (1..PCI_MAX_DEVICES)
+            .map(|device| PciAddress::new(0, device, 0))
+            .find(|address| !self.addresses.iter().any(|a| a.device == address.device))?;
+        self.reserve_address(address)
+    }
+
+    /// Reserve `address`, e.g. for another function of a device.
+    /// Returns `None` if it is already allocated.
+    pub fn reserve_address(&mut self, address: PciAddress) -> Option<PciAddress> {
+        if address.bus != 0 || !self.addresses.insert(address) {
+            return None;
+        }
+        Some(address)
+    }
+
+    /// Free `address`.
+    pub fn free_address(&mut self, address: PciAddress) {
+        if address != PciAddress::new(0, 0, 0) {
+            self.addresses.remove(&address);
+        }
+    }
+
+    fn config_read(&self, addr: u64, data: &mut [u8]) {
+        if addr < PCI_CONFIG_SIZE {
+            return self.host_bridge.read(addr, data);
+        }
+        if self
+            .io
+            .read(GuestAddress(addr), data, IoType::PciConfig)
+            .is_err()
+        {
+            // Nothing at this address.
+            for byte in data.iter_mut() {
+                *byte = 0xff;
+            }
+        }
+    }
+
+    fn config_write(&mut self, addr: u64, data: &[u8]) {
+        if addr < PCI_CONFIG_SIZE {
+            return self.host_bridge.write(addr, data);
+        }
+        let _ = self.io.write(GuestAddress(addr), data, IoType::PciConfig);
+    }
+
+    /// Return the `IoType::PciConfig` address selected by the CF8 register.
+    fn cf8_address(&self, offset: GuestUsize) -> Option<u64> {
+        if self.config_address & 0x8000_0000 == 0 {
+            return None;
+        }
+        let bdf = u64::from((self.config_address >> 8) & 0xffff);
+        let reg = u64::from(self.config_address & 0xfc);
+        Some(bdf << 12 | reg | (offset - 4))
+    }
+}
+
+impl AcpiDevice for PciRoot {
+    fn acpi_name(&self) -> String {
+        "PCI0".to_string()
+    }
+
+    fn hid(&self) -> String {
+        "PNP0A08".to_string()
+    }
+
+    fn aml(&self) -> Vec<u8> {
+        // Name (_CID, EisaId ("PNP0A03"))
+        vec![0x08, b'_', b'C', b'I', b'D', 0x0c, 0x41, 0xd0, 0x0a, 0x03]
+    }
+
+    fn table_entry(&self) -> Option<AcpiTableEntry> {
+        Some(AcpiTableEntry::PciEcam {
+            segment: 0,
+            start_bus: 0,
+            end_bus: 0,
+        })
+    }
+}
+
+impl Device for PciRoot {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn read(
+        &mut self,
+        res_index: usize,
+        offset: GuestUsize,
+        data: &mut [u8],
+        _io_type: IoType,
+    ) -> device::Result<()> {
+        match (res_index, offset) {
+            (0, 0) if data.len() == 4 => data.copy_from_slice(&self.config_address.to_le_bytes()),
+            (0, 4..=7) => match self.cf8_address(offset) {
+                Some(addr) => self.config_read(addr, data),
+                None => data.iter_mut().for_each(|byte| *byte = 0xff),
+            },
+            (0, _) => return Err(device::Error::InvalidAccessWidth(data.len())),
+            _ => self.config_read(offset, data),
+        }
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        res_index: usize,
+        offset: GuestUsize,
+        data: &[u8],
+        _io_type: IoType,
+    ) -> device::Result<()> {
+        match (res_index, offset) {
+            (0, 0) if data.len() == 4 => {
+                let mut config_address = [0u8; 4];
+                config_address.copy_from_slice(data);
+                self.config_address = u32::from_le_bytes(config_address);
+            }
+            (0, 4..=7) => {
+                if let Some(addr) = self.cf8_address(offset) {
+                    self.config_write(addr, data);
+                }
+            }
+            (0, _) => return Err(device::Error::InvalidAccessWidth(data.len())),
+            _ => self.config_write(offset, data),
+        }
+        Ok(())
+    }
+
+    fn set_resources(&mut self, res: &[IoResource], _irq: Option<IrqResource>) {
+        self.ecam = res.get(1).and_then(|res| res.addr);
+    }
+
+    fn acpi(&self) -> Option<&dyn AcpiDevice> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate vm_allocator;
+    extern crate vmm_sys_util;
+
+    use self::vm_allocator::SystemAllocator;
+    use self::vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+    use super::*;
+    use crate::device_manager::DeviceManager;
+    use std::sync::Mutex;
+
+    struct Nic {
+        config: PciConfiguration,
+        last_access: Option<(usize, GuestUsize)>,
+    }
+
+    impl PciDevice for Nic {
+        fn name(&self) -> String {
+            "nic".to_string()
+        }
+        fn config(&self) -> &PciConfiguration {
+            &self.config
+        }
+        fn config_mut(&mut self) -> &mut PciConfiguration {
+            &mut self.config
+        }
+        fn bar_read(
+            &mut self,
+            bar: usize,
+            offset: GuestUsize,
+            data: &mut [u8],
+        ) -> device::Result<()> {
+            self.last_access = Some((bar, offset));
+            data[0] = bar as u8;
+            Ok(())
+        }
+        fn bar_write(
+            &mut self,
+            bar: usize,
+            offset: GuestUsize,
+            _data: &[u8],
+        ) -> device::Result<()> {
+            self.last_access = Some((bar, offset));
+            Ok(())
+        }
+    }
+
+    fn nic() -> Nic {
+        let mut config = PciConfiguration::new(0x1af4, 0x1041, 0x02, 0x00, 0x00);
+        config
+            .add_bar(PciBar::new(0, 0x1000, PciBarType::Mmio32))
+            .unwrap();
+        config
+            .add_bar(PciBar::new(2, 0x4000, PciBarType::Mmio64))
+            .unwrap();
+        Nic {
+            config,
+            last_access: None,
+        }
+    }
+
+    fn read_u32(config: &PciConfiguration, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        config.read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_address() {
+        let address = PciAddress::new(0, 3, 1);
+        assert_eq!(address.bdf(), 0x19);
+        assert_eq!(PciAddress::from_bdf(0x19), address);
+        assert_eq!(address.config_address(0x10), GuestAddress(0x19010));
+        assert_eq!(address.to_string(), "00:03.1");
+    }
+
+    #[test]
+    fn test_bar_sizing() {
+        let mut config = nic().config;
+        assert!(config
+            .add_bar(PciBar::new(3, 0x1000, PciBarType::Mmio32))
+            .is_none());
+        assert!(config
+            .add_bar(PciBar::new(4, 0x1001, PciBarType::Mmio32))
+            .is_none());
+
+        config.set_bar_address(0, GuestAddress(0xfe00_0000));
+        assert_eq!(read_u32(&config, 0x10), 0xfe00_0000);
+        config.write(0x10, &[0xff; 4]);
+        assert_eq!(read_u32(&config, 0x10), 0xffff_f000);
+
+        config.write(0x18, &[0xff; 4]);
+        config.write(0x1c, &[0xff; 4]);
+        assert_eq!(read_u32(&config, 0x18), 0xffff_c004);
+        assert_eq!(read_u32(&config, 0x1c), 0xffff_ffff);
+        config.set_bar_address(2, GuestAddress(0x1_0000_0000));
+        assert_eq!(config.bar_address(2), Some(GuestAddress(0x1_0000_0000)));
+
+        // Read-only registers.
+        config.write(0, &[0; 4]);
+        assert_eq!(read_u32(&config, 0), 0x1041_1af4);
+    }
+
+    #[test]
+    fn test_pci_root() {
+        let mut sys_res = SystemAllocator::new(
+            Some(GuestAddress(0x100)),
+            Some(0x10000),
+            GuestAddress(0x1000_0000),
+            0x1000_0000,
+            5,
+        )
+        .unwrap();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let mut root = PciRoot::new("pci0", dev_mgr.io_dispatcher());
+        let address = root.allocate_address().unwrap();
+        assert_eq!(address, PciAddress::new(0, 1, 0));
+        let root = Arc::new(Mutex::new(root));
+        let mut root_req = root.lock().unwrap().resources();
+        dev_mgr
+            .register_device(root.clone(), None, &mut root_req, None)
+            .unwrap();
+        let ecam = root.lock().unwrap().ecam_base().unwrap();
+
+        let function = Arc::new(Mutex::new(PciFunction::new(address, Box::new(nic()))));
+        let mut res_req = function.lock().unwrap().resources();
+        dev_mgr
+            .register_device(
+                function.clone(),
+                Some(root.clone()),
+                &mut res_req,
+                Some(IrqResource::Legacy(None)),
+            )
+            .unwrap();
+        assert_eq!(dev_mgr.path_of("nic"), Some("/pci0/nic".to_string()));
+
+        // CF8/CFC mechanism.
+        let mut data = [0u8; 4];
+        let cf8 = GuestAddress(PCI_CONFIG_IO_PORT);
+        let cfc = GuestAddress(PCI_CONFIG_IO_PORT + 4);
+        dev_mgr
+            .write(cf8, &0x8000_0800u32.to_le_bytes(), IoType::Pio)
+            .unwrap();
+        dev_mgr.read(cfc, &mut data, IoType::Pio).unwrap();
+        assert_eq!(u32::from_le_bytes(data), 0x1041_1af4);
+        dev_mgr
+            .write(cf8, &0x8000_0000u32.to_le_bytes(), IoType::Pio)
+            .unwrap();
+        dev_mgr.read(cfc, &mut data, IoType::Pio).unwrap();
+        assert_eq!(u32::from_le_bytes(data), 0x0d57_8086);
+        // Nothing at 00:02.0.
+        dev_mgr
+            .write(cf8, &0x8000_1000u32.to_le_bytes(), IoType::Pio)
+            .unwrap();
+        dev_mgr.read(cfc, &mut data, IoType::Pio).unwrap();
+        assert_eq!(data, [0xff; 4]);
+
+        // ECAM, the BARs got their allocated addresses and the interrupt line.
+        let bar0 = res_req[1].addr.unwrap();
+        dev_mgr
+            .read(GuestAddress(ecam.0 + 0x8010), &mut data, IoType::Mmio)
+            .unwrap();
+        assert_eq!(u64::from(u32::from_le_bytes(data)), bar0.0);
+        dev_mgr
+            .read(GuestAddress(ecam.0 + 0x803c), &mut data, IoType::Mmio)
+            .unwrap();
+        assert_eq!(data[..2], [5, 1]);
+
+        // BAR accesses reach the function.
+        let bar2 = res_req[2].addr.unwrap();
+        dev_mgr
+            .read(GuestAddress(bar2.0 + 0x10), &mut data, IoType::Mmio)
+            .unwrap();
+        assert_eq!(data[0], 2);
+
+        // Sizing a BAR does not move it, nor does programming it while memory
+        // decoding is disabled.
+        let event = EventFd::new(EFD_NONBLOCK).unwrap();
+        dev_mgr.set_relocation_event(event.try_clone().unwrap());
+        let bar0_reg = GuestAddress(ecam.0 + 0x8010);
+        let command_reg = GuestAddress(ecam.0 + 0x8004);
+        dev_mgr.write(bar0_reg, &[0xff; 4], IoType::Mmio).unwrap();
+        dev_mgr.write(bar0_reg, &[0; 4], IoType::Mmio).unwrap();
+        let new = GuestAddress(0x1800_0000);
+        dev_mgr
+            .write(bar0_reg, &(new.0 as u32).to_le_bytes(), IoType::Mmio)
+            .unwrap();
+        assert!(event.read().is_err());
+        dev_mgr.relocate_moved_resources().unwrap();
+        dev_mgr.read(bar0, &mut data, IoType::Mmio).unwrap();
+
+        // Enabling memory decoding moves the BAR to its programmed address.
+        dev_mgr
+            .write(command_reg, &COMMAND_MEMORY.to_le_bytes(), IoType::Mmio)
+            .unwrap();
+        assert_eq!(event.read().unwrap(), 1);
+        // The BAR keeps being decoded at its old address until relocated.
+        dev_mgr.read(bar0, &mut data, IoType::Mmio).unwrap();
+        assert!(dev_mgr.read(new, &mut data, IoType::Mmio).is_err());
+        dev_mgr.relocate_moved_resources().unwrap();
+        dev_mgr.read(new, &mut data, IoType::Mmio).unwrap();
+        assert_eq!(data[0], 0);
+        assert!(dev_mgr.read(bar0, &mut data, IoType::Mmio).is_err());
+        dev_mgr.relocate_moved_resources().unwrap();
+
+        // With memory decoding enabled, programming the BAR moves it at once.
+        dev_mgr
+            .write(bar0_reg, &(bar0.0 as u32).to_le_bytes(), IoType::Mmio)
+            .unwrap();
+        assert_eq!(event.read().unwrap(), 1);
+        dev_mgr.relocate_moved_resources().unwrap();
+        dev_mgr.read(bar0, &mut data, IoType::Mmio).unwrap();
+        assert!(dev_mgr.read(new, &mut data, IoType::Mmio).is_err());
+    }
+
+    #[test]
+    fn test_pci_function() {
+        let mut function = PciFunction::new(PciAddress::new(0, 1, 0), Box::new(nic()));
+        let res = function.resources();
+        let mut data = [0u8; 4];
+        match function.read(3, 0, &mut data, IoType::Mmio) {
+            Err(device::Error::InvalidResource(3)) => (),
+            _ => panic!("expected InvalidResource"),
+        }
+        assert!(function.write(3, 0, &data, IoType::Mmio).is_err());
+
+        // Lines the Interrupt Line register cannot hold read as unknown.
+        function.set_resources(&res, Some(IrqResource::Legacy(Some(300))));
+        function
+            .read(0, 0x3c, &mut data, IoType::PciConfig)
+            .unwrap();
+        assert_eq!(data[..2], [0xff, 1]);
+        function.set_resources(&res, Some(IrqResource::SharedLegacy(Some(10))));
+        function
+            .read(0, 0x3c, &mut data, IoType::PciConfig)
+            .unwrap();
+        assert_eq!(data[..2], [10, 1]);
+    }
+}
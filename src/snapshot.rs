@@ -0,0 +1,367 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Device manager state save and restore.
+//!
+//! [DeviceManagerState](struct.DeviceManagerState.html) captures everything
+//! needed to rebuild a [DeviceManager](../device_manager/struct.DeviceManager.html)
+//! with the exact same address placement and routing: the
+//! `SystemAllocator` state and, for each device, its name, parent bus,
+//! resources, IRQ and device specific state. It serializes to a versioned
+//! binary blob suitable for snapshots and live migration.
+
+extern crate vm_allocator;
+
+use self::vm_allocator::SystemAllocatorState;
+use crate::device;
+use crate::device::{IoResource, IoType, IrqResource};
+use crate::device_manager::{Error, Result};
+use vm_memory::{GuestAddress, GuestUsize};
+
+/// Magic number starting a serialized `DeviceManagerState`.
+const MAGIC: &[u8; 4] = b"VMDM";
+
+/// Version of the `DeviceManagerState` serialization format.
+pub const STATE_VERSION: u32 = 1;
+
+/// Trait for device specific state that can be saved and restored.
+pub trait Snapshot {
+    /// Version of the state layout returned by `save()`.
+    fn version(&self) -> u32;
+    /// Save the device state.
+    fn save(&self) -> Vec<u8>;
+    /// Restore a device state saved by `save()` with layout `version`.
+    ///
+    /// Return error if the version is not supported or the state is invalid.
+    fn restore(&mut self, version: u32, data: &[u8]) -> device::Result<()>;
+}
+
+/// Saved state of one device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceState {
+    /// Device name.
+    pub name: String,
+    /// Name of the parent bus, if any.
+    pub parent_bus: Option<String>,
+    /// Allocated IO resources of the device, in `set_resources()` order.
+    pub resource: Vec<IoResource>,
+    /// Allocated interrupt of the device, if it requested one.
+    pub irq: Option<IrqResource>,
+    /// Device specific state layout version and data, if any.
+    pub state: Option<(u32, Vec<u8>)>,
+}
+
+/// Saved state of a `DeviceManager`.
+///
+/// Devices are listed parents first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceManagerState {
+    /// System allocator state.
+    pub allocator: SystemAllocatorState,
+    /// Device states.
+    pub devices: Vec<DeviceState>,
+}
+
+impl DeviceManagerState {
+    /// Serialize the state into a versioned binary blob.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut w = Writer(Vec::new());
+        w.0.extend_from_slice(MAGIC);
+        w.u32(STATE_VERSION);
+
+        w.ranges(&self.allocator.io_ranges);
+        w.ranges(&self.allocator.mmio_ranges);
+        w.u32(self.allocator.irqs.len() as u32);
+        for irq in self.allocator.irqs.iter() {
+            w.u32(*irq);
+        }
+        w.u32(self.allocator.shared_irqs.len() as u32);
+        for (irq, users) in self.allocator.shared_irqs.iter() {
+            w.u32(*irq);
+            w.u32(*users);
+        }
+
+        w.u32(self.devices.len() as u32);
+        for dev in self.devices.iter() {
+            w.string(&dev.name);
+            w.option(dev.parent_bus.as_ref(), |w, name| w.string(name));
+            w.u32(dev.resource.len() as u32);
+            for res in dev.resource.iter() {
+                w.option(res.addr.as_ref(), |w, addr| w.u64(addr.0));
+                w.u64(res.size);
+                w.io_type(res.res_type);
+            }
+            w.option(dev.irq.as_ref(), |w, irq| w.irq(irq));
+            w.option(dev.state.as_ref(), |w, (version, data)| {
+                w.u32(*version);
+                w.bytes(data);
+            });
+        }
+        w.0
+    }
+
+    /// Deserialize a blob returned by `serialize()`.
+    ///
+    /// Return `Error::InvalidSnapshot` if the blob is malformed or comes from
+    /// an unsupported format version.
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        let mut r = Reader(data);
+        if r.take(MAGIC.len())? != MAGIC || r.u32()? != STATE_VERSION {
+            return Err(Error::InvalidSnapshot);
+        }
+
+        let mut allocator = SystemAllocatorState {
+            io_ranges: r.ranges()?,
+            mmio_ranges: r.ranges()?,
+            irqs: Vec::new(),
+            shared_irqs: Vec::new(),
+        };
+        for _ in 0..r.u32()? {
+            allocator.irqs.push(r.u32()?);
+        }
+        for _ in 0..r.u32()? {
+            allocator.shared_irqs.push((r.u32()?, r.u32()?));
+        }
+
+        let count = r.u32()?;
+        let mut devices = Vec::new();
+        for _ in 0..count {
+            let name = r.string()?;
+            let parent_bus = r.option(|r| r.string())?;
+            let mut resource = Vec::new();
+            for _ in 0..r.u32()? {
+                let addr = r.option(|r| r.u64().map(GuestAddress))?;
+                let size = r.u64()?;
+                resource.push(IoResource::new(addr, size, r.io_type()?));
+            }
+            let irq = r.option(|r| r.irq())?;
+            let state = r.option(|r| Ok((r.u32()?, r.bytes()?)))?;
+            devices.push(DeviceState {
+                name,
+                parent_bus,
+                resource,
+                irq,
+                state,
+            });
+        }
+
+        if !r.0.is_empty() {
+            return Err(Error::InvalidSnapshot);
+        }
+        Ok(DeviceManagerState { allocator, devices })
+    }
+}
+
+/// Little endian encoder.
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.0.extend_from_slice(v);
+    }
+
+    fn string(&mut self, v: &str) {
+        self.bytes(v.as_bytes());
+    }
+
+    fn option<T, F: FnOnce(&mut Self, &T)>(&mut self, v: Option<&T>, f: F) {
+        match v {
+            Some(v) => {
+                self.u8(1);
+                f(self, v);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn ranges(&mut self, v: &[(GuestAddress, GuestUsize)]) {
+        self.u32(v.len() as u32);
+        for (addr, size) in v.iter() {
+            self.u64(addr.0);
+            self.u64(*size);
+        }
+    }
+
+    fn io_type(&mut self, v: IoType) {
+        match v {
+            IoType::Pio => self.u8(0),
+            IoType::Mmio => self.u8(1),
+            IoType::PhysicalMmio => self.u8(2),
+            IoType::PciConfig => self.u8(3),
+            IoType::Custom(id) => {
+                self.u8(4);
+                self.u32(id);
+            }
+        }
+    }
+
+    fn irq(&mut self, v: &IrqResource) {
+        let (kind, base, count) = match *v {
+            IrqResource::Legacy(irq) => (0, irq, 1),
+            IrqResource::Msi { base, count } => (1, base, count),
+            IrqResource::MsiX { base, count } => (2, base, count),
+            IrqResource::SharedLegacy(irq) => (3, irq, 1),
+        };
+        self.u8(kind);
+        self.option(base.as_ref(), |w, base| w.u32(*base));
+        self.u32(count);
+    }
+}
+
+/// Little endian decoder.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(Error::InvalidSnapshot);
+        }
+        let (v, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(v)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut v = [0u8; 4];
+        v.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(v))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut v = [0u8; 8];
+        v.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(v))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|_| Error::InvalidSnapshot)
+    }
+
+    fn option<T, F: FnOnce(&mut Self) -> Result<T>>(&mut self, f: F) -> Result<Option<T>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => f(self).map(Some),
+            _ => Err(Error::InvalidSnapshot),
+        }
+    }
+
+    fn ranges(&mut self) -> Result<Vec<(GuestAddress, GuestUsize)>> {
+        let mut v = Vec::new();
+        for _ in 0..self.u32()? {
+            v.push((GuestAddress(self.u64()?), self.u64()?));
+        }
+        Ok(v)
+    }
+
+    fn io_type(&mut self) -> Result<IoType> {
+        match self.u8()? {
+            0 => Ok(IoType::Pio),
+            1 => Ok(IoType::Mmio),
+            2 => Ok(IoType::PhysicalMmio),
+            3 => Ok(IoType::PciConfig),
+            4 => Ok(IoType::Custom(self.u32()?)),
+            _ => Err(Error::InvalidSnapshot),
+        }
+    }
+
+    fn irq(&mut self) -> Result<IrqResource> {
+        let kind = self.u8()?;
+        let base = self.option(|r| r.u32())?;
+        let count = self.u32()?;
+        match kind {
+            0 => Ok(IrqResource::Legacy(base)),
+            1 => Ok(IrqResource::Msi { base, count }),
+            2 => Ok(IrqResource::MsiX { base, count }),
+            3 => Ok(IrqResource::SharedLegacy(base)),
+            _ => Err(Error::InvalidSnapshot),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> DeviceManagerState {
+        DeviceManagerState {
+            allocator: SystemAllocatorState {
+                io_ranges: vec![(GuestAddress(0xcf8), 8)],
+                mmio_ranges: vec![(GuestAddress(0x1fffe000), 0x1000)],
+                irqs: vec![5, 6, 7],
+                shared_irqs: vec![(10, 1), (11, 0)],
+            },
+            devices: vec![
+                DeviceState {
+                    name: "bus".to_string(),
+                    parent_bus: None,
+                    resource: vec![
+                        IoResource::new(Some(GuestAddress(0xcf8)), 8, IoType::Pio),
+                        IoResource::new(Some(GuestAddress(0x8000)), 0x1000, IoType::PciConfig),
+                        IoResource::new(Some(GuestAddress(0)), 0x1000, IoType::Custom(2)),
+                    ],
+                    irq: Some(IrqResource::SharedLegacy(Some(10))),
+                    state: None,
+                },
+                DeviceState {
+                    name: "dev".to_string(),
+                    parent_bus: Some("bus".to_string()),
+                    resource: vec![IoResource::new(
+                        Some(GuestAddress(0x1fffe000)),
+                        0x1000,
+                        IoType::Mmio,
+                    )],
+                    irq: Some(IrqResource::MsiX {
+                        base: Some(5),
+                        count: 3,
+                    }),
+                    state: Some((2, vec![1, 2, 3])),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_state_serialization() {
+        let state = state();
+        let data = state.serialize();
+        assert_eq!(DeviceManagerState::deserialize(&data).unwrap(), state);
+    }
+
+    #[test]
+    fn test_state_invalid() {
+        let data = state().serialize();
+
+        // Truncated blob.
+        assert!(DeviceManagerState::deserialize(&data[..data.len() - 1]).is_err());
+        // Trailing data.
+        let mut longer = data.clone();
+        longer.push(0);
+        assert!(DeviceManagerState::deserialize(&longer).is_err());
+        // Unknown format version.
+        let mut newer = data.clone();
+        newer[4] = STATE_VERSION as u8 + 1;
+        assert!(DeviceManagerState::deserialize(&newer).is_err());
+    }
+}
@@ -0,0 +1,704 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! virtio-mmio transport.
+//!
+//! A virtio device implements the [VirtioDevice](trait.VirtioDevice.html)
+//! backend trait and gets wrapped into a
+//! [VirtioMmioDevice](struct.VirtioMmioDevice.html), the `Device` handling the
+//! version 2 register layout of the virtio-mmio transport. The transport
+//! requests its MMIO window and a legacy IRQ, and hands the configured queues,
+//! their notification eventfds and a [VirtioInterrupt](struct.VirtioInterrupt.html)
+//! over to the backend once the driver is ready.
+
+extern crate vmm_sys_util;
+
+use self::vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+use crate::device;
+use crate::device::{Device, IoResource, IoType, IrqResource};
+use crate::fdt::FdtProperty;
+use crate::interrupt::InterruptSourceGroup;
+use crate::ioevent::Doorbell;
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use vm_memory::{GuestAddress, GuestUsize};
+
+/// Size of the MMIO window of a virtio-mmio device.
+pub const VIRTIO_MMIO_SIZE: GuestUsize = 0x1000;
+
+/// Interrupt status bit of used buffer notifications.
+pub const VIRTIO_MMIO_INT_VRING: u32 = 0x1;
+/// Interrupt status bit of configuration change notifications.
+pub const VIRTIO_MMIO_INT_CONFIG: u32 = 0x2;
+
+/// Feature bit of virtio 1.0 compliant devices, always offered.
+pub const VIRTIO_F_VERSION_1: u32 = 32;
+
+const MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
+const MMIO_VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0;
+
+const REG_MAGIC_VALUE: u64 = 0x000;
+const REG_VERSION: u64 = 0x004;
+const REG_DEVICE_ID: u64 = 0x008;
+const REG_VENDOR_ID: u64 = 0x00c;
+const REG_DEVICE_FEATURES: u64 = 0x010;
+const REG_DEVICE_FEATURES_SEL: u64 = 0x014;
+const REG_DRIVER_FEATURES: u64 = 0x020;
+const REG_DRIVER_FEATURES_SEL: u64 = 0x024;
+const REG_QUEUE_SEL: u64 = 0x030;
+const REG_QUEUE_NUM_MAX: u64 = 0x034;
+const REG_QUEUE_NUM: u64 = 0x038;
+const REG_QUEUE_READY: u64 = 0x044;
+const REG_QUEUE_NOTIFY: u64 = 0x050;
+const REG_INTERRUPT_STATUS: u64 = 0x060;
+const REG_INTERRUPT_ACK: u64 = 0x064;
+const REG_STATUS: u64 = 0x070;
+const REG_QUEUE_DESC_LOW: u64 = 0x080;
+const REG_QUEUE_DESC_HIGH: u64 = 0x084;
+const REG_QUEUE_DRIVER_LOW: u64 = 0x090;
+const REG_QUEUE_DRIVER_HIGH: u64 = 0x094;
+const REG_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const REG_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const REG_CONFIG_GENERATION: u64 = 0x0fc;
+const REG_CONFIG: u64 = 0x100;
+
+const STATUS_ACKNOWLEDGE: u32 = 0x01;
+const STATUS_DRIVER: u32 = 0x02;
+const STATUS_DRIVER_OK: u32 = 0x04;
+const STATUS_FEATURES_OK: u32 = 0x08;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+const STATUS_FAILED: u32 = 0x80;
+
+/// Configuration of a virtqueue, as set up by the driver.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VirtioQueue {
+    /// Maximum size supported by the device.
+    pub max_size: u16,
+    /// Size selected by the driver.
+    pub size: u16,
+    /// Whether the driver enabled the queue.
+    pub ready: bool,
+    /// Guest address of the descriptor table.
+    pub desc_table: GuestAddress,
+    /// Guest address of the available ring.
+    pub avail_ring: GuestAddress,
+    /// Guest address of the used ring.
+    pub used_ring: GuestAddress,
+}
+
+impl VirtioQueue {
+    fn new(max_size: u16) -> Self {
+        VirtioQueue {
+            max_size,
+            size: max_size,
+            ready: false,
+            desc_table: GuestAddress(0),
+            avail_ring: GuestAddress(0),
+            used_ring: GuestAddress(0),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        !self.ready || (self.size > 0 && self.size <= self.max_size && self.size.is_power_of_two())
+    }
+}
+
+/// Interrupt of a virtio-mmio device, with its interrupt status register.
+pub struct VirtioInterrupt {
+    status: AtomicU32,
+    group: Mutex<Option<Arc<dyn InterruptSourceGroup>>>,
+}
+
+impl VirtioInterrupt {
+    fn new() -> Self {
+        VirtioInterrupt {
+            status: AtomicU32::new(0),
+            group: Mutex::new(None),
+        }
+    }
+
+    /// Notify the driver of used buffers.
+    pub fn signal_used_queue(&self) -> io::Result<()> {
+        self.signal(VIRTIO_MMIO_INT_VRING)
+    }
+
+    /// Notify the driver of a configuration space change.
+    pub fn signal_config_change(&self) -> io::Result<()> {
+        self.signal(VIRTIO_MMIO_INT_CONFIG)
+    }
+
+    /// Return the interrupt status register.
+    pub fn status(&self) -> u32 {
+        self.status.load(Ordering::SeqCst)
+    }
+
+    // The status and the line level change under the same lock, so that the
+    // line never ends up deasserted with status bits set.
+    fn signal(&self, bits: u32) -> io::Result<()> {
+        let group = self.lock();
+        self.status.fetch_or(bits, Ordering::SeqCst);
+        match group.as_ref() {
+            Some(group) => group.assert(0),
+            None => Ok(()),
+        }
+    }
+
+    // The line stays asserted until the driver acknowledges every status bit.
+    fn ack(&self, bits: u32) -> io::Result<()> {
+        let group = self.lock();
+        if self.status.fetch_and(!bits, Ordering::SeqCst) & !bits != 0 {
+            return Ok(());
+        }
+        match group.as_ref() {
+            Some(group) => group.deassert(0),
+            None => Ok(()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Arc<dyn InterruptSourceGroup>>> {
+        self.group.lock().expect("Failed to acquire interrupt lock")
+    }
+}
+
+/// Trait for virtio device backends.
+#[allow(unused_variables)]
+pub trait VirtioDevice: Send {
+    /// Get the device name.
+    fn name(&self) -> String;
+    /// Return the virtio device type, e.g. 1 for a network device.
+    fn device_type(&self) -> u32;
+    /// Return the maximum size of each virtqueue.
+    fn queue_max_sizes(&self) -> Vec<u16>;
+    /// Return the offered feature bits.
+    fn features(&self) -> u64;
+    /// Acknowledge the feature bits negotiated by the driver.
+    fn ack_features(&mut self, features: u64) {}
+    /// Read the device configuration space at `offset`.
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+    /// Write the device configuration space at `offset`.
+    fn write_config(&mut self, offset: u64, data: &[u8]) {}
+    /// Start the device with the virtqueues set up by the driver and their
+    /// notification events.
+    fn activate(
+        &mut self,
+        queues: Vec<VirtioQueue>,
+        events: Vec<Arc<EventFd>>,
+        interrupt: Arc<VirtioInterrupt>,
+    ) -> device::Result<()>;
+    /// Stop the device, the driver reset it.
+    fn reset(&mut self) {}
+}
+
+/// `Device` exposing a virtio backend through the virtio-mmio transport.
+pub struct VirtioMmioDevice {
+    device: Box<dyn VirtioDevice>,
+    queues: Vec<VirtioQueue>,
+    events: Vec<Arc<EventFd>>,
+    interrupt: Arc<VirtioInterrupt>,
+    queue_select: u32,
+    device_features_select: u32,
+    driver_features_select: u32,
+    driver_features: u64,
+    status: u32,
+    config_generation: u32,
+    activated: bool,
+}
+
+impl VirtioMmioDevice {
+    /// Create the transport of `device`, with one notification event per
+    /// virtqueue.
+    pub fn new(device: Box<dyn VirtioDevice>) -> io::Result<Self> {
+        let queues: Vec<VirtioQueue> = device
+            .queue_max_sizes()
+            .into_iter()
+            .map(VirtioQueue::new)
+            .collect();
+        let mut events = Vec::new();
+        for _ in queues.iter() {
+            events.push(Arc::new(EventFd::new(EFD_NONBLOCK)?));
+        }
+        Ok(VirtioMmioDevice {
+            device,
+            queues,
+            events,
+            interrupt: Arc::new(VirtioInterrupt::new()),
+            queue_select: 0,
+            device_features_select: 0,
+            driver_features_select: 0,
+            driver_features: 0,
+            status: 0,
+            config_generation: 0,
+            activated: false,
+        })
+    }
+
+    /// Return the resource requests of the transport, to be registered.
+    pub fn resources(&self) -> Vec<IoResource> {
+        vec![IoResource::new(None, VIRTIO_MMIO_SIZE, IoType::Mmio)]
+    }
+
+    /// Return the interrupt request of the transport, to be registered.
+    pub fn irq_request(&self) -> IrqResource {
+        IrqResource::Legacy(None)
+    }
+
+    /// Return the interrupt of the device.
+    pub fn interrupt(&self) -> Arc<VirtioInterrupt> {
+        self.interrupt.clone()
+    }
+
+    /// Return the notification event of each virtqueue.
+    pub fn queue_events(&self) -> Vec<Arc<EventFd>> {
+        self.events.clone()
+    }
+
+    /// Notify the driver of a configuration space change.
+    pub fn config_changed(&mut self) -> io::Result<()> {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        self.interrupt.signal_config_change()
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | 1 << VIRTIO_F_VERSION_1
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut VirtioQueue> {
+        // Queues can only be set up before the driver is done.
+        if self.status & STATUS_DRIVER_OK != 0 {
+            return None;
+        }
+        self.queues.get_mut(self.queue_select as usize)
+    }
+
+    fn set_status(&mut self, status: u32) {
+        if status == 0 {
+            return self.reset();
+        }
+        let added = status & !self.status;
+        if added & STATUS_FEATURES_OK != 0 && self.driver_features & !self.device_features() != 0 {
+            // Unsupported features, leave FEATURES_OK unset for the driver to
+            // notice.
+            self.status = status & !STATUS_FEATURES_OK;
+            return;
+        }
+        if added & STATUS_FEATURES_OK != 0 {
+            self.device.ack_features(self.driver_features);
+        }
+        self.status = status;
+        if added & STATUS_DRIVER_OK != 0 && !self.activated {
+            self.activate();
+        }
+    }
+
+    fn activate(&mut self) {
+        let ready = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+        if self.status & ready != ready
+            || self.status & STATUS_FAILED != 0
+            || !self.queues.iter().all(VirtioQueue::is_valid)
+        {
+            self.status |= STATUS_DEVICE_NEEDS_RESET;
+            return;
+        }
+        match self.device.activate(
+            self.queues.clone(),
+            self.events.clone(),
+            self.interrupt.clone(),
+        ) {
+            Ok(()) => self.activated = true,
+            Err(_) => self.status |= STATUS_DEVICE_NEEDS_RESET,
+        }
+    }
+
+    fn reset(&mut self) {
+        if self.activated {
+            self.device.reset();
+            self.activated = false;
+        }
+        for queue in self.queues.iter_mut() {
+            *queue = VirtioQueue::new(queue.max_size);
+        }
+        let _ = self.interrupt.ack(!0);
+        self.queue_select = 0;
+        self.device_features_select = 0;
+        self.driver_features_select = 0;
+        self.driver_features = 0;
+        self.status = 0;
+    }
+
+    fn read_reg(&self, offset: GuestUsize) -> device::Result<u32> {
+        let features = self.device_features();
+        let queue = self.queues.get(self.queue_select as usize);
+        let value = match offset {
+            REG_MAGIC_VALUE => MMIO_MAGIC_VALUE,
+            REG_VERSION => MMIO_VERSION,
+            REG_DEVICE_ID => self.device.device_type(),
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => match self.device_features_select {
+                0 => features as u32,
+                1 => (features >> 32) as u32,
+                _ => 0,
+            },
+            REG_QUEUE_NUM_MAX => queue.map_or(0, |q| u32::from(q.max_size)),
+            REG_QUEUE_READY => queue.map_or(0, |q| q.ready as u32),
+            REG_INTERRUPT_STATUS => self.interrupt.status(),
+            REG_STATUS => self.status,
+            REG_CONFIG_GENERATION => self.config_generation,
+            _ => return Err(device::Error::ReservedRegister(offset)),
+        };
+        Ok(value)
+    }
+
+    fn write_reg(&mut self, offset: GuestUsize, value: u32) -> device::Result<()> {
+        fn set_low(addr: &mut GuestAddress, value: u32) {
+            addr.0 = addr.0 & !0xffff_ffff | u64::from(value);
+        }
+        fn set_high(addr: &mut GuestAddress, value: u32) {
+            addr.0 = addr.0 & 0xffff_ffff | u64::from(value) << 32;
+        }
+
+        match offset {
+            REG_DEVICE_FEATURES_SEL => self.device_features_select = value,
+            REG_DRIVER_FEATURES => match self.driver_features_select {
+                0 => self.driver_features = self.driver_features & !0xffff_ffff | u64::from(value),
+                1 => {
+                    self.driver_features =
+                        self.driver_features & 0xffff_ffff | u64::from(value) << 32
+                }
+                _ => (),
+            },
+            REG_DRIVER_FEATURES_SEL => self.driver_features_select = value,
+            REG_QUEUE_SEL => self.queue_select = value,
+            REG_QUEUE_NUM => {
+                if let Some(q) = self.selected_queue() {
+                    q.size = value as u16
+                }
+            }
+            REG_QUEUE_READY => {
+                if let Some(q) = self.selected_queue() {
+                    q.ready = value == 1
+                }
+            }
+            // Not a doorbell: the queue index is out of range.
+            REG_QUEUE_NOTIFY => (),
+            REG_INTERRUPT_ACK => self
+                .interrupt
+                .ack(value)
+                .map_err(|e| device::Error::Internal(e.to_string()))?,
+            REG_STATUS => self.set_status(value),
+            REG_QUEUE_DESC_LOW => {
+                if let Some(q) = self.selected_queue() {
+                    set_low(&mut q.desc_table, value)
+                }
+            }
+            REG_QUEUE_DESC_HIGH => {
+                if let Some(q) = self.selected_queue() {
+                    set_high(&mut q.desc_table, value)
+                }
+            }
+            REG_QUEUE_DRIVER_LOW => {
+                if let Some(q) = self.selected_queue() {
+                    set_low(&mut q.avail_ring, value)
+                }
+            }
+            REG_QUEUE_DRIVER_HIGH => {
+                if let Some(q) = self.selected_queue() {
+                    set_high(&mut q.avail_ring, value)
+                }
+            }
+            REG_QUEUE_DEVICE_LOW => {
+                if let Some(q) = self.selected_queue() {
+                    set_low(&mut q.used_ring, value)
+                }
+            }
+            REG_QUEUE_DEVICE_HIGH => {
+                if let Some(q) = self.selected_queue() {
+                    set_high(&mut q.used_ring, value)
+                }
+            }
+            _ => return Err(device::Error::ReservedRegister(offset)),
+        }
+        Ok(())
+    }
+}
+
+impl Device for VirtioMmioDevice {
+    fn name(&self) -> String {
+        self.device.name()
+    }
+
+    fn read(
+        &mut self,
+        _res_index: usize,
+        offset: GuestUsize,
+        data: &mut [u8],
+        _io_type: IoType,
+    ) -> device::Result<()> {
+        if offset >= REG_CONFIG {
+            self.device.read_config(offset - REG_CONFIG, data);
+            return Ok(());
+        }
+        if data.len() != 4 {
+            return Err(device::Error::InvalidAccessWidth(data.len()));
+        }
+        data.copy_from_slice(&self.read_reg(offset)?.to_le_bytes());
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        _res_index: usize,
+        offset: GuestUsize,
+        data: &[u8],
+        _io_type: IoType,
+    ) -> device::Result<()> {
+        if offset >= REG_CONFIG {
+            self.device.write_config(offset - REG_CONFIG, data);
+            return Ok(());
+        }
+        if data.len() != 4 {
+            return Err(device::Error::InvalidAccessWidth(data.len()));
+        }
+        let mut value = [0u8; 4];
+        value.copy_from_slice(data);
+        self.write_reg(offset, u32::from_le_bytes(value))
+    }
+
+    fn set_resources(&mut self, _res: &[IoResource], _irq: Option<IrqResource>) {}
+
+    fn is_virtio_mmio(&self) -> bool {
+        true
+    }
+
+    fn fdt_properties(&self) -> Vec<FdtProperty> {
+        vec![
+            FdtProperty::string("compatible", "virtio,mmio"),
+            FdtProperty::empty("dma-coherent"),
+        ]
+    }
+
+    fn set_interrupt_group(&mut self, group: Arc<dyn InterruptSourceGroup>) {
+        *self.interrupt.lock() = Some(group);
+    }
+
+    fn doorbells(&self) -> Vec<Doorbell> {
+        self.events
+            .iter()
+            .enumerate()
+            .map(|(i, event)| Doorbell::new(0, REG_QUEUE_NOTIFY, 4, Some(i as u64), event.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate vm_allocator;
+
+    use self::vm_allocator::SystemAllocator;
+    use super::*;
+    use crate::device_manager::DeviceManager;
+    use crate::interrupt::{InterruptEvent, InterruptRecorder, IrqLine, LegacyIrq};
+    use std::thread;
+
+    #[derive(Default)]
+    struct Activation {
+        features: u64,
+        queues: Vec<VirtioQueue>,
+        events: Vec<Arc<EventFd>>,
+        interrupt: Option<Arc<VirtioInterrupt>>,
+        reset: bool,
+    }
+
+    struct Block {
+        state: Arc<Mutex<Activation>>,
+    }
+
+    impl VirtioDevice for Block {
+        fn name(&self) -> String {
+            "blk".to_string()
+        }
+        fn device_type(&self) -> u32 {
+            2
+        }
+        fn queue_max_sizes(&self) -> Vec<u16> {
+            vec![256, 128]
+        }
+        fn features(&self) -> u64 {
+            0x4
+        }
+        fn ack_features(&mut self, features: u64) {
+            self.state.lock().unwrap().features = features;
+        }
+        fn read_config(&self, offset: u64, data: &mut [u8]) {
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = offset as u8 + i as u8;
+            }
+        }
+        fn activate(
+            &mut self,
+            queues: Vec<VirtioQueue>,
+            events: Vec<Arc<EventFd>>,
+            interrupt: Arc<VirtioInterrupt>,
+        ) -> device::Result<()> {
+            let mut state = self.state.lock().unwrap();
+            state.queues = queues;
+            state.events = events;
+            state.interrupt = Some(interrupt);
+            Ok(())
+        }
+        fn reset(&mut self) {
+            self.state.lock().unwrap().reset = true;
+        }
+    }
+
+    #[test]
+    fn test_virtio_mmio() {
+        let mut sys_res =
+            SystemAllocator::new(None, None, GuestAddress(0x1000_0000), 0x1000_0000, 5).unwrap();
+        let mut dev_mgr = DeviceManager::new(&mut sys_res);
+        let recorder = Arc::new(InterruptRecorder::default());
+        dev_mgr.set_interrupt_backend(recorder.clone()).unwrap();
+
+        let state = Arc::new(Mutex::new(Activation::default()));
+        let block = Box::new(Block {
+            state: state.clone(),
+        });
+        let dev = Arc::new(Mutex::new(VirtioMmioDevice::new(block).unwrap()));
+        let mut res_req = dev.lock().unwrap().resources();
+        let irq = dev.lock().unwrap().irq_request();
+        dev_mgr
+            .register_device(dev.clone(), None, &mut res_req, Some(irq))
+            .unwrap();
+        let base = res_req[0].addr.unwrap().0;
+        assert_eq!(
+            dev_mgr.virtio_mmio_cmdline(),
+            vec![format!("virtio_mmio.device=0x1000@{:#x}:5", base)]
+        );
+
+        let read = |offset: u64| {
+            let mut data = [0u8; 4];
+            dev_mgr
+                .read(GuestAddress(base + offset), &mut data, IoType::Mmio)
+                .unwrap();
+            u32::from_le_bytes(data)
+        };
+        let write = |offset: u64, value: u32| {
+            dev_mgr
+                .write(
+                    GuestAddress(base + offset),
+                    &value.to_le_bytes(),
+                    IoType::Mmio,
+                )
+                .unwrap()
+        };
+        assert_eq!(read(REG_MAGIC_VALUE), MMIO_MAGIC_VALUE);
+        assert_eq!(read(REG_VERSION), 2);
+        assert_eq!(read(REG_DEVICE_ID), 2);
+        assert_eq!(read(REG_CONFIG + 4), 0x0706_0504);
+
+        // Feature negotiation.
+        write(REG_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        assert_eq!(read(REG_DEVICE_FEATURES), 0x4);
+        write(REG_DEVICE_FEATURES_SEL, 1);
+        assert_eq!(read(REG_DEVICE_FEATURES), 0x1);
+        write(REG_DRIVER_FEATURES_SEL, 1);
+        write(REG_DRIVER_FEATURES, 0x3);
+        write(
+            REG_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK,
+        );
+        assert_eq!(read(REG_STATUS) & STATUS_FEATURES_OK, 0);
+        write(REG_DRIVER_FEATURES, 0x1);
+        write(REG_DRIVER_FEATURES_SEL, 0);
+        write(REG_DRIVER_FEATURES, 0x4);
+        write(
+            REG_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK,
+        );
+        assert_eq!(state.lock().unwrap().features, 0x1_0000_0004);
+
+        // Queue setup.
+        write(REG_QUEUE_SEL, 1);
+        assert_eq!(read(REG_QUEUE_NUM_MAX), 128);
+        write(REG_QUEUE_NUM, 64);
+        write(REG_QUEUE_DESC_LOW, 0x1000);
+        write(REG_QUEUE_DESC_HIGH, 0x1);
+        write(REG_QUEUE_DRIVER_LOW, 0x2000);
+        write(REG_QUEUE_DEVICE_LOW, 0x3000);
+        write(REG_QUEUE_READY, 1);
+        write(
+            REG_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK,
+        );
+        {
+            let state = state.lock().unwrap();
+            assert_eq!(state.queues.len(), 2);
+            assert!(!state.queues[0].ready);
+            assert_eq!(
+                state.queues[1],
+                VirtioQueue {
+                    max_size: 128,
+                    size: 64,
+                    ready: true,
+                    desc_table: GuestAddress(0x1_0000_1000),
+                    avail_ring: GuestAddress(0x2000),
+                    used_ring: GuestAddress(0x3000),
+                }
+            );
+        }
+
+        // Queue notifications ring the queue events.
+        write(REG_QUEUE_NOTIFY, 1);
+        let events = state.lock().unwrap().events.clone();
+        assert_eq!(events[1].read().unwrap(), 1);
+        assert!(events[0].read().is_err());
+
+        // Interrupt status.
+        let interrupt = state.lock().unwrap().interrupt.clone().unwrap();
+        interrupt.signal_used_queue().unwrap();
+        dev.lock().unwrap().config_changed().unwrap();
+        assert_eq!(read(REG_INTERRUPT_STATUS), 0x3);
+        assert_eq!(read(REG_CONFIG_GENERATION), 1);
+        write(REG_INTERRUPT_ACK, VIRTIO_MMIO_INT_VRING);
+        write(REG_INTERRUPT_ACK, VIRTIO_MMIO_INT_CONFIG);
+        assert_eq!(read(REG_INTERRUPT_STATUS), 0);
+        assert_eq!(
+            recorder.take_events(),
+            vec![
+                InterruptEvent::Level(5, true),
+                InterruptEvent::Level(5, false)
+            ]
+        );
+
+        // Reset.
+        write(REG_STATUS, 0);
+        assert!(state.lock().unwrap().reset);
+        assert_eq!(read(REG_STATUS), 0);
+        assert_eq!(read(REG_QUEUE_READY), 0);
+    }
+
+    #[test]
+    fn test_virtio_interrupt_race() {
+        let recorder = Arc::new(InterruptRecorder::default());
+        let line = Arc::new(IrqLine::new(5, recorder));
+        let interrupt = Arc::new(VirtioInterrupt::new());
+        *interrupt.lock() = Some(Arc::new(LegacyIrq::new(line.clone())));
+
+        // The line is asserted exactly when status bits are set, whatever the
+        // interleaving of the device signaling and the driver acknowledging.
+        let device = interrupt.clone();
+        let signaler = thread::spawn(move || {
+            for _ in 0..10000 {
+                device.signal_used_queue().unwrap();
+            }
+        });
+        for _ in 0..10000 {
+            interrupt.ack(VIRTIO_MMIO_INT_VRING).unwrap();
+        }
+        signaler.join().unwrap();
+        assert_eq!(line.is_asserted(), interrupt.status() != 0);
+        interrupt.ack(VIRTIO_MMIO_INT_VRING).unwrap();
+        assert!(!line.is_asserted());
+    }
+}
@@ -33,7 +33,7 @@ pub type Result<T> = result::Result<T, Error>;
 ///       assert_eq!(pool.allocate(None, 0x100), Some(GuestAddress(0x10c00)));
 ///   });
 /// ```
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct AddressAllocator {
     base: GuestAddress,
     end: GuestAddress,
@@ -191,6 +191,32 @@ impl AddressAllocator {
             }
         }
     }
+
+    /// Returns the allocated address ranges, sorted by address.
+    pub fn allocated_ranges(&self) -> Vec<(GuestAddress, GuestUsize)> {
+        self.ranges
+            .iter()
+            .filter(|(_, &size)| size != 0)
+            .map(|(&address, &size)| (address, size))
+            .collect()
+    }
+
+    /// Replaces all allocated address ranges with `ranges`, e.g. when restoring
+    /// a saved allocation state. Returns `None` and leaves the allocator
+    /// untouched if one of the ranges does not fit in the managed region.
+    pub fn set_allocated_ranges(&mut self, ranges: &[(GuestAddress, GuestUsize)]) -> Option<()> {
+        for &(address, size) in ranges {
+            if size == 0 || address < self.base || address.checked_add(size - 1)? > self.end {
+                return None;
+            }
+        }
+
+        self.ranges.retain(|_, size| *size == 0);
+        for &(address, size) in ranges {
+            self.ranges.insert(address, size);
+        }
+        Some(())
+    }
 }
 
 #[cfg(test)]
@@ -351,4 +377,31 @@ mod tests {
             Some(GuestAddress(0x1200))
         );
     }
+
+    #[test]
+    fn allocated_ranges_save_and_restore() {
+        let mut pool = AddressAllocator::new(GuestAddress(0x1000), 0x1000, Some(0x100)).unwrap();
+        assert_eq!(
+            pool.allocate(Some(GuestAddress(0x1200)), 0x800),
+            Some(GuestAddress(0x1200))
+        );
+        assert_eq!(pool.allocate(None, 0x100), Some(GuestAddress(0x1000)));
+        let ranges = pool.allocated_ranges();
+        assert_eq!(
+            ranges,
+            vec![(GuestAddress(0x1000), 0x100), (GuestAddress(0x1200), 0x800)]
+        );
+
+        let mut restored =
+            AddressAllocator::new(GuestAddress(0x1000), 0x1000, Some(0x100)).unwrap();
+        assert_eq!(restored.set_allocated_ranges(&ranges), Some(()));
+        assert_eq!(restored, pool);
+
+        // Out of pool ranges are rejected.
+        assert_eq!(
+            restored.set_allocated_ranges(&[(GuestAddress(0x1f00), 0x200)]),
+            None
+        );
+        assert_eq!(restored, pool);
+    }
 }
@@ -17,4 +17,4 @@ mod address;
 mod system;
 
 pub use crate::address::AddressAllocator;
-pub use crate::system::SystemAllocator;
+pub use crate::system::{SystemAllocator, SystemAllocatorState};
@@ -7,6 +7,8 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
+use std::collections::{BTreeMap, BTreeSet};
+
 use vm_memory::{GuestAddress, GuestUsize};
 
 use crate::address::AddressAllocator;
@@ -33,13 +35,18 @@ fn pagesize() -> usize {
 ///           5).unwrap();
 ///    assert_eq!(allocator.allocate_irq(), Some(5));
 ///    assert_eq!(allocator.allocate_irq(), Some(6));
+///    assert_eq!(allocator.reserve_irq(4), Some(4));
+///    assert_eq!(allocator.reserve_irq(6), None);
 ///    assert_eq!(allocator.allocate_mmio_addresses(None, 0x1000), Some(GuestAddress(0x1fffe000)));
 ///
 /// ```
 pub struct SystemAllocator {
     io_address_space: Option<AddressAllocator>,
     mmio_address_space: AddressAllocator,
-    next_irq: u32,
+    first_irq: u32,
+    irqs: BTreeSet<u32>,
+    // Shared irq lines, with their number of users.
+    shared_irqs: BTreeMap<u32, u32>,
 }
 
 impl SystemAllocator {
@@ -51,7 +58,8 @@ impl SystemAllocator {
     /// * `io_size` - The size of IO memory.
     /// * `mmio_base` - The starting address of MMIO memory.
     /// * `mmio_size` - The size of MMIO memory.
-    /// * `first_irq` - The first irq number to give out.
+    /// * `first_irq` - The first irq number to give out. Lower numbers can
+    ///   still be reserved explicitly.
     pub fn new(
         io_base: Option<GuestAddress>,
         io_size: Option<GuestUsize>,
@@ -67,18 +75,101 @@ impl SystemAllocator {
                 None
             },
             mmio_address_space: AddressAllocator::new(mmio_base, mmio_size, Some(page_size))?,
-            next_irq: first_irq,
+            first_irq,
+            irqs: BTreeSet::new(),
+            shared_irqs: BTreeMap::new(),
         })
     }
 
-    /// Reserves the next available system irq number.
+    /// Reserves the lowest available system irq number.
     pub fn allocate_irq(&mut self) -> Option<u32> {
-        if let Some(irq_num) = self.next_irq.checked_add(1) {
-            self.next_irq = irq_num;
-            Some(irq_num - 1)
-        } else {
-            None
+        self.allocate_irqs(1)
+    }
+
+    /// Reserves `count` consecutive irq numbers, returning the first one.
+    pub fn allocate_irqs(&mut self, count: u32) -> Option<u32> {
+        if count == 0 {
+            return None;
+        }
+        let mut base = self.first_irq;
+        // Move past the last allocated irq of each candidate block.
+        while let Some(irq) = self.last_allocated(base, base.checked_add(count)?) {
+            base = irq.checked_add(1)?;
+        }
+        self.reserve_irqs(base, count)
+    }
+
+    /// Reserves the irq number `irq`.
+    /// Returns `None` if it is already allocated.
+    pub fn reserve_irq(&mut self, irq: u32) -> Option<u32> {
+        self.reserve_irqs(irq, 1)
+    }
+
+    /// Reserves `count` consecutive irq numbers starting from `base`.
+    /// Returns `None` if any of them is already allocated.
+    pub fn reserve_irqs(&mut self, base: u32, count: u32) -> Option<u32> {
+        let end = base.checked_add(count)?;
+        if count == 0 || self.last_allocated(base, end).is_some() {
+            return None;
         }
+        self.irqs.extend(base..end);
+        Some(base)
+    }
+
+    /// Sets aside the irq numbers `irqs` as lines shared by level triggered
+    /// devices, e.g. PCI INTx.
+    /// Returns `None` if any of them is already allocated as a non shared irq.
+    pub fn reserve_shared_irqs(&mut self, irqs: &[u32]) -> Option<()> {
+        if irqs.iter().any(|irq| self.irqs.contains(irq)) {
+            return None;
+        }
+        for irq in irqs.iter() {
+            self.shared_irqs.entry(*irq).or_insert(0);
+        }
+        Some(())
+    }
+
+    /// Adds a user to the least used shared irq line.
+    pub fn allocate_shared_irq(&mut self) -> Option<u32> {
+        let (&irq, _) = self
+            .shared_irqs
+            .iter()
+            .min_by_key(|&(irq, users)| (*users, *irq))?;
+        self.reserve_shared_irq(irq)
+    }
+
+    /// Adds a user to the shared irq line `irq`, setting it aside if it is
+    /// not allocated yet.
+    /// Returns `None` if it is already allocated as a non shared irq.
+    pub fn reserve_shared_irq(&mut self, irq: u32) -> Option<u32> {
+        if self.irqs.contains(&irq) {
+            return None;
+        }
+        *self.shared_irqs.entry(irq).or_insert(0) += 1;
+        Some(irq)
+    }
+
+    /// Free an irq number, or remove a user of a shared irq line.
+    pub fn free_irq(&mut self, irq: u32) {
+        match self.shared_irqs.get_mut(&irq) {
+            Some(users) => *users = users.saturating_sub(1),
+            None => {
+                self.irqs.remove(&irq);
+            }
+        }
+    }
+
+    /// Free `count` consecutive irq numbers starting from `base`.
+    pub fn free_irqs(&mut self, base: u32, count: u32) {
+        for irq in base..base.saturating_add(count) {
+            self.free_irq(irq);
+        }
+    }
+
+    fn last_allocated(&self, base: u32, end: u32) -> Option<u32> {
+        let irq = self.irqs.range(base..end).next_back();
+        let shared = self.shared_irqs.range(base..end).next_back();
+        irq.max(shared.map(|(irq, _)| irq)).cloned()
     }
 
     /// Reserves a section of `size` bytes of IO address space.
@@ -114,4 +205,109 @@ impl SystemAllocator {
     pub fn free_mmio_addresses(&mut self, address: GuestAddress, size: GuestUsize) {
         self.mmio_address_space.free(address, size)
     }
+
+    /// Returns the current allocation state, to be restored later on by
+    /// `restore_state()`.
+    pub fn save_state(&self) -> SystemAllocatorState {
+        SystemAllocatorState {
+            io_ranges: self
+                .io_address_space
+                .as_ref()
+                .map(|io| io.allocated_ranges())
+                .unwrap_or_default(),
+            mmio_ranges: self.mmio_address_space.allocated_ranges(),
+            irqs: self.irqs.iter().cloned().collect(),
+            shared_irqs: self
+                .shared_irqs
+                .iter()
+                .map(|(irq, users)| (*irq, *users))
+                .collect(),
+        }
+    }
+
+    /// Restores an allocation state returned by `save_state()`.
+    /// Returns `None` and leaves the allocator untouched if the state does not
+    /// fit in the managed address spaces.
+    pub fn restore_state(&mut self, state: &SystemAllocatorState) -> Option<()> {
+        let mut io_address_space = self.io_address_space.clone();
+        match io_address_space.as_mut() {
+            Some(io) => io.set_allocated_ranges(&state.io_ranges)?,
+            None if state.io_ranges.is_empty() => (),
+            None => return None,
+        }
+        let mut mmio_address_space = self.mmio_address_space.clone();
+        mmio_address_space.set_allocated_ranges(&state.mmio_ranges)?;
+
+        self.io_address_space = io_address_space;
+        self.mmio_address_space = mmio_address_space;
+        self.irqs = state.irqs.iter().cloned().collect();
+        self.shared_irqs = state.shared_irqs.iter().cloned().collect();
+        Some(())
+    }
+}
+
+/// Allocation state of a `SystemAllocator`.
+///
+/// Saving and restoring it reproduces the same address placement, e.g. for
+/// snapshots or live migration.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SystemAllocatorState {
+    /// Allocated IO address ranges.
+    pub io_ranges: Vec<(GuestAddress, GuestUsize)>,
+    /// Allocated MMIO address ranges.
+    pub mmio_ranges: Vec<(GuestAddress, GuestUsize)>,
+    /// Allocated irq numbers, in increasing order.
+    pub irqs: Vec<u32>,
+    /// Shared irq lines with their number of users, in increasing order.
+    pub shared_irqs: Vec<(u32, u32)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn irq_allocation() {
+        let mut allocator =
+            SystemAllocator::new(None, None, GuestAddress(0x1000_0000), 0x1000_0000, 5).unwrap();
+        assert_eq!(allocator.reserve_irq(4), Some(4));
+        assert_eq!(allocator.reserve_irq(4), None);
+        assert_eq!(allocator.reserve_irqs(7, 2), Some(7));
+        assert_eq!(allocator.allocate_irq(), Some(5));
+        // Blocks skip over the reserved irqs.
+        assert_eq!(allocator.allocate_irqs(2), Some(9));
+        assert_eq!(allocator.allocate_irq(), Some(6));
+        assert_eq!(allocator.reserve_irqs(3, 2), None);
+
+        // Freed irqs are handed out again.
+        allocator.free_irqs(7, 2);
+        assert_eq!(allocator.allocate_irqs(2), Some(7));
+        allocator.free_irq(5);
+        assert_eq!(allocator.save_state().irqs, vec![4, 6, 7, 8, 9, 10]);
+        assert_eq!(allocator.allocate_irq(), Some(5));
+    }
+
+    #[test]
+    fn shared_irq_allocation() {
+        let mut allocator =
+            SystemAllocator::new(None, None, GuestAddress(0x1000_0000), 0x1000_0000, 5).unwrap();
+        assert_eq!(allocator.allocate_shared_irq(), None);
+        assert_eq!(allocator.reserve_irq(10), Some(10));
+        assert_eq!(allocator.reserve_shared_irqs(&[9, 10]), None);
+        assert_eq!(allocator.reserve_shared_irqs(&[6, 7]), Some(()));
+
+        // Users are spread over the least used lines.
+        assert_eq!(allocator.allocate_shared_irq(), Some(6));
+        assert_eq!(allocator.allocate_shared_irq(), Some(7));
+        assert_eq!(allocator.allocate_shared_irq(), Some(6));
+        assert_eq!(allocator.reserve_shared_irq(7), Some(7));
+        assert_eq!(allocator.reserve_shared_irq(10), None);
+        assert_eq!(allocator.reserve_irq(6), None);
+        assert_eq!(allocator.allocate_irqs(3), Some(11));
+
+        allocator.free_irq(6);
+        allocator.free_irq(6);
+        assert_eq!(allocator.save_state().shared_irqs, vec![(6, 0), (7, 2)]);
+        assert_eq!(allocator.allocate_shared_irq(), Some(6));
+    }
 }